@@ -0,0 +1,347 @@
+use std::fmt;
+
+use crate::error::*;
+use crate::mem::ram::Ram;
+use crate::mem::*;
+use super::*;
+
+pub const IMAGE_WIDTH: usize = 128;
+pub const IMAGE_HEIGHT: usize = 112;
+// the photo is developed to bank 0 as 16x14 tiles
+pub const IMAGE_ADDR: usize = 0x0100;
+
+const REGISTER_SELECT: u8 = 0x10;
+const REGISTER_COUNT: usize = 0x36;
+const REG_CONTROL: usize = 0x00;
+const REG_EDGE_MODE: usize = 0x01;
+const REG_EXPOSURE_HIGH: usize = 0x02;
+const REG_EXPOSURE_LOW: usize = 0x03;
+const REG_EDGE_RATIO: usize = 0x04;
+const REG_MATRIX: usize = 0x06;
+
+const CONTROL_CAPTURE: u8 = 0b0000_0001;
+const EDGE_MODE_N: u8 = 0b1000_0000;
+const EDGE_RATIO_INVERT: u8 = 0b0000_1000;
+
+// edge enhancement ratio in quarters: 50%, 75%, 100%, 125%, 200%, 300%, 400%, 500%
+const EDGE_RATIO: [i32; 8] = [2, 3, 4, 5, 8, 12, 16, 20];
+
+// called at the start of a capture to fill a 128x112 grayscale frame, 0 is black and 255 is white
+pub type SensorFn = Box<dyn FnMut(&mut [u8])>;
+
+struct Sensor(SensorFn);
+
+impl fmt::Debug for Sensor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sensor")
+    }
+}
+
+#[derive(Debug)]
+pub struct PocketCamera {
+    ram_enable: bool, // only gates writes, the ram is always readable
+    rom_bank: u8,
+    ram_bank: u8, // bit 4 maps the sensor registers instead of ram
+    registers: Vec<u8>,
+    frame: Vec<u8>,
+    capture_cycles: u32, // left until the photo is developed
+    sensor: Option<Sensor>,
+}
+
+impl PocketCamera {
+    pub fn new() -> PocketCamera {
+        PocketCamera {
+            ram_enable: false,
+            rom_bank: 1u8,
+            ram_bank: 0u8,
+            registers: vec![0u8; REGISTER_COUNT],
+            frame: vec![0u8; IMAGE_WIDTH * IMAGE_HEIGHT],
+            capture_cycles: 0u32,
+            sensor: None,
+        }
+    }
+
+    pub fn set_sensor<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut [u8]) + 'static,
+    {
+        self.sensor = Some(Sensor(Box::new(f)));
+    }
+
+    pub fn capturing(&self) -> bool {
+        self.capture_cycles > 0
+    }
+
+    pub fn ram_writable(&self) -> bool {
+        self.ram_enable
+    }
+
+    // Some when the sensor registers are mapped or the ram is busy with a capture
+    pub fn read_port(&self, addr: u16) -> Option<u8> {
+        if self.ram_bank & REGISTER_SELECT == 0 {
+            return if self.capturing() { Some(0x00) } else { None };
+        }
+        // only the control register can be read back, the others are mirrored every 0x80 bytes
+        match (addr as usize - EXTERNAL_RAM_ADDR_TOP) & 0x7f {
+            REG_CONTROL => Some((self.registers[REG_CONTROL] & 0x06) | self.capturing() as u8),
+            _ => Some(0x00),
+        }
+    }
+
+    pub fn write_port(&mut self, addr: u16, val: u8) -> bool {
+        if self.ram_bank & REGISTER_SELECT == 0 {
+            return false;
+        }
+        match (addr as usize - EXTERNAL_RAM_ADDR_TOP) & 0x7f {
+            REG_CONTROL => {
+                self.registers[REG_CONTROL] = val & 0x07;
+                if val & CONTROL_CAPTURE != 0 && !self.capturing() {
+                    self.capture();
+                } else if val & CONTROL_CAPTURE == 0 {
+                    self.capture_cycles = 0;
+                }
+            },
+            reg if reg < REGISTER_COUNT => self.registers[reg] = val,
+            _ => {},
+        }
+        true
+    }
+
+    fn exposure(&self) -> u32 {
+        u16::from_be_bytes([self.registers[REG_EXPOSURE_HIGH], self.registers[REG_EXPOSURE_LOW]]) as u32
+    }
+
+    // the sensor takes its picture when the capture starts, the photo shows up when it ends
+    fn capture(&mut self) {
+        if let Some(Sensor(f)) = &mut self.sensor {
+            f(&mut self.frame);
+        }
+        let n = if self.registers[REG_EDGE_MODE] & EDGE_MODE_N != 0 { 0 } else { 512 };
+        // 1MHz cycles
+        self.capture_cycles = (32446 + n + 16 * self.exposure()) * 4;
+    }
+
+    fn pixel(&self, x: i32, y: i32) -> i32 {
+        let x = x.clamp(0, IMAGE_WIDTH as i32 - 1) as usize;
+        let y = y.clamp(0, IMAGE_HEIGHT as i32 - 1) as usize;
+        let v = self.frame[y * IMAGE_WIDTH + x] as i32;
+        let v = if self.registers[REG_EDGE_RATIO] & EDGE_RATIO_INVERT != 0 { 255 - v } else { v };
+        (v as i64 * self.exposure() as i64 / 0x1000).min(0xff) as i32
+    }
+
+    // 2bit colors of the photo after edge enhancement and the dithering matrix
+    pub fn process(&self) -> Vec<u8> {
+        let vh = (self.registers[REG_EDGE_MODE] >> 5) & 0x03;
+        let ratio = EDGE_RATIO[(self.registers[REG_EDGE_RATIO] >> 4) as usize & 0x07];
+        let mut image = Vec::with_capacity(IMAGE_WIDTH * IMAGE_HEIGHT);
+        for y in 0..IMAGE_HEIGHT as i32 {
+            for x in 0..IMAGE_WIDTH as i32 {
+                let v = self.pixel(x, y);
+                let mut edge = 0;
+                if vh & 0x01 != 0 {
+                    edge += 2 * v - self.pixel(x - 1, y) - self.pixel(x + 1, y);
+                }
+                if vh & 0x02 != 0 {
+                    edge += 2 * v - self.pixel(x, y - 1) - self.pixel(x, y + 1);
+                }
+                let v = (v + edge * ratio / 8).clamp(0, 0xff) as u8;
+                let matrix = REG_MATRIX + ((y as usize & 0x03) * 4 + (x as usize & 0x03)) * 3;
+                let color = match v {
+                    v if v < self.registers[matrix] => 3,
+                    v if v < self.registers[matrix + 1] => 2,
+                    v if v < self.registers[matrix + 2] => 1,
+                    _ => 0,
+                };
+                image.push(color);
+            }
+        }
+        image
+    }
+
+    fn develop(&self, ram: &mut Ram) {
+        let image = self.process();
+        for y in 0..IMAGE_HEIGHT {
+            for x in (0..IMAGE_WIDTH).step_by(8) {
+                let (low, high) = (0..8).fold((0u8, 0u8), |(low, high), i| {
+                    let color = image[y * IMAGE_WIDTH + x + i];
+                    ((low << 1) | (color & 0x01), (high << 1) | (color >> 1))
+                });
+                let offset = IMAGE_ADDR + ((y / 8) * (IMAGE_WIDTH / 8) + x / 8) * 16 + (y % 8) * 2;
+                ram.set(offset, low);
+                ram.set(offset + 1, high);
+            }
+        }
+    }
+}
+
+impl Mapper for PocketCamera {
+    fn rom_offset(&self, addr: u16) -> usize {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => addr as usize,
+            _ => self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - ROM_BANK_ADDR_TOP),
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if self.ram_bank & REGISTER_SELECT != 0 {
+            return None;
+        }
+        Some((self.ram_bank & 0x0f) as usize * RAM_BANK_SIZE + addr as usize - EXTERNAL_RAM_ADDR_TOP)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = (val & 0x3f).max(1),
+            0x4000..=0x5fff => self.ram_bank = val & 0x1f,
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self, ram: &mut Ram, cycles: u32) {
+        if !self.capturing() {
+            return;
+        }
+        self.capture_cycles = self.capture_cycles.saturating_sub(cycles);
+        if !self.capturing() {
+            self.registers[REG_CONTROL] &= !CONTROL_CAPTURE;
+            self.develop(ram);
+        }
+    }
+
+    fn read_ram(&self, ram: &Ram, addr: u16) -> u8 {
+        self.read_port(addr).unwrap_or_else(|| read_mapped_ram(self, ram, addr))
+    }
+
+    fn write_ram(&mut self, ram: &mut Ram, addr: u16, val: u8) {
+        if !self.write_port(addr, val) && self.ram_writable() {
+            write_mapped_ram(self, ram, addr, val);
+        }
+    }
+}
+
+impl Default for PocketCamera {
+    fn default() -> Self {
+        PocketCamera::new()
+    }
+}
+
+// decodes a photo stored as 16x14 tiles at offset into 128x112 2bit colors
+pub fn read_image(ram: &Ram, offset: usize) -> Vec<u8> {
+    let mut image = vec![0u8; IMAGE_WIDTH * IMAGE_HEIGHT];
+    for (i, color) in image.iter_mut().enumerate() {
+        let (x, y) = (i % IMAGE_WIDTH, i / IMAGE_WIDTH);
+        let addr = offset + ((y / 8) * (IMAGE_WIDTH / 8) + x / 8) * 16 + (y % 8) * 2;
+        let bit = 7 - (x % 8);
+        let low = ram.get(addr).unwrap_or(0) >> bit & 0x01;
+        let high = ram.get(addr + 1).unwrap_or(0) >> bit & 0x01;
+        *color = (high << 1) | low;
+    }
+    image
+}
+
+// the next whitespace separated word of a pnm header, skipping comments
+fn token(data: &[u8], pos: &mut usize) -> GBResult<String> {
+    loop {
+        match data.get(*pos) {
+            Some(b'#') => {
+                while data.get(*pos).is_some_and(|b| *b != b'\n') {
+                    *pos += 1;
+                }
+            },
+            Some(b) if b.is_ascii_whitespace() => *pos += 1,
+            Some(_) => break,
+            None => return Err(GBError::InvalidData),
+        }
+    }
+    let top = *pos;
+    while data.get(*pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+    Ok(String::from_utf8_lossy(&data[top..*pos]).to_string())
+}
+
+// loads a binary (P5) or plain (P2) pgm file and scales it to the sensor resolution
+pub fn load_pgm(data: &[u8]) -> GBResult<Vec<u8>> {
+    let mut pos = 0usize;
+    let number = |s: String| s.parse::<usize>().map_err(|_| GBError::InvalidData);
+
+    let magic = token(data, &mut pos)?;
+    let width = number(token(data, &mut pos)?)?;
+    let height = number(token(data, &mut pos)?)?;
+    let max = number(token(data, &mut pos)?)?;
+    if width == 0 || height == 0 || max == 0 || max > 0xff {
+        return Err(GBError::InvalidData);
+    }
+    let size = width.checked_mul(height).ok_or(GBError::InvalidData)?;
+    let pixels: Vec<u8> = match magic.as_str() {
+        "P5" => {
+            // a single whitespace separates the header from the raster
+            let end = (pos + 1).checked_add(size).ok_or(GBError::InvalidData)?;
+            let raster = data.get(pos + 1..end).ok_or(GBError::InvalidData)?;
+            raster.to_vec()
+        },
+        "P2" => (0..size).map(|_| token(data, &mut pos).and_then(number).map(|v| v as u8)).collect::<GBResult<_>>()?,
+        _ => return Err(GBError::InvalidData),
+    };
+    Ok((0..IMAGE_WIDTH * IMAGE_HEIGHT)
+        .map(|i| {
+            let (x, y) = (i % IMAGE_WIDTH * width / IMAGE_WIDTH, i / IMAGE_WIDTH * height / IMAGE_HEIGHT);
+            (pixels[y * width + x] as usize * 0xff / max) as u8
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::mem::rom::Rom;
+
+    #[test]
+    fn test_load_pgm() {
+        let mut data = b"P5\n# comment\n2 1\n15\n".to_vec();
+        data.extend_from_slice(&[0, 15]);
+        let frame = load_pgm(&data).unwrap();
+        assert_eq!((frame[0], frame[IMAGE_WIDTH - 1], frame[IMAGE_WIDTH * IMAGE_HEIGHT - 1]), (0, 0xff, 0xff));
+
+        let frame = load_pgm(b"P2 1 2 255 10 200").unwrap();
+        assert_eq!((frame[0], frame[IMAGE_WIDTH * IMAGE_HEIGHT - 1]), (10, 200));
+        assert!(load_pgm(b"P5 2 2 255 \x00").is_err());
+        assert!(load_pgm(b"P5 4294967296 4294967297 255 \x00").is_err());
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut rom = Rom::new(test_rom(0xfc, 0x05, 0x04));
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        assert_eq!(cart.ram().len(), 0x20000);
+        // left half black, right half white
+        cart.set_camera_sensor(|frame: &mut [u8]| {
+            for (i, v) in frame.iter_mut().enumerate() {
+                *v = if i % IMAGE_WIDTH < IMAGE_WIDTH / 2 { 0x00 } else { 0xff };
+            }
+        });
+
+        cart.write(0x4000, 0x10).unwrap();
+        cart.write(0xa002, 0x10).unwrap();
+        cart.write(0xa003, 0x00).unwrap();
+        for i in 0..16 {
+            cart.write(0xa006 + i * 3, 0x40).unwrap();
+            cart.write(0xa007 + i * 3, 0x80).unwrap();
+            cart.write(0xa008 + i * 3, 0xc0).unwrap();
+        }
+        cart.write(0xa000, 0x01).unwrap();
+        assert_eq!(cart.read(0xa000).unwrap(), 0x01);
+        cart.write(0x4000, 0x00).unwrap();
+        assert_eq!(cart.read(0xa100).unwrap(), 0x00);
+
+        cart.tick((32446 + 512 + 16 * 0x1000) * 4);
+        cart.write(0x4000, 0x10).unwrap();
+        assert_eq!(cart.read(0xa000).unwrap(), 0x00);
+        let image = read_image(cart.ram(), IMAGE_ADDR);
+        assert_eq!((image[0], image[IMAGE_WIDTH - 1]), (3, 0));
+        assert_eq!((image[IMAGE_WIDTH * 111], image[IMAGE_WIDTH * 112 - 1]), (3, 0));
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::mbc3::CPU_CLOCK_HZ;
+
+// the time source of the cartridge clocks. it turns the cycles run by the cpu into
+// the cycles the rtc of the cartridge runs, and tells the unix time for the save footer
+pub trait Clock: fmt::Debug {
+    // unix time in seconds
+    fn now(&self) -> u64;
+    fn advance(&mut self, cycles: u32) -> u64;
+}
+
+// unix time in seconds of the host, 0 in the browser which has to pass it in
+pub fn host_time() -> u64 {
+    if cfg!(target_arch = "wasm32") {
+        return 0;
+    }
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// follows the wall clock of the host whatever the emulation speed is. the browser has no
+// Instant, there it runs with the emulated cycles
+#[derive(Debug)]
+pub struct HostClock {
+    last: Option<Instant>,
+    nanos: u128, // not yet converted to cycles
+}
+
+impl HostClock {
+    pub fn new() -> HostClock {
+        HostClock {
+            last: if cfg!(target_arch = "wasm32") { None } else { Some(Instant::now()) },
+            nanos: 0u128,
+        }
+    }
+}
+
+impl Default for HostClock {
+    fn default() -> Self {
+        HostClock::new()
+    }
+}
+
+impl Clock for HostClock {
+    fn now(&self) -> u64 {
+        host_time()
+    }
+
+    fn advance(&mut self, cycles: u32) -> u64 {
+        let last = match self.last {
+            Some(last) => last,
+            None => return cycles as u64,
+        };
+        let now = Instant::now();
+        self.nanos += now.duration_since(last).as_nanos();
+        self.last = Some(now);
+        let second = Duration::from_secs(1).as_nanos();
+        let cycles = self.nanos * CPU_CLOCK_HZ as u128 / second;
+        self.nanos -= cycles * second / CPU_CLOCK_HZ as u128;
+        cycles as u64
+    }
+}
+
+// runs with the emulated cycles from a given unix time, so a run does not depend on when it is executed
+#[derive(Debug)]
+pub struct CycleClock {
+    start: u64,
+    cycles: u64,
+}
+
+impl CycleClock {
+    pub fn new(start: u64) -> CycleClock {
+        CycleClock {
+            start,
+            cycles: 0u64,
+        }
+    }
+
+    // starts at the time of the host, so save footers get real stamps and catch up with other emulators
+    pub fn from_host() -> CycleClock {
+        CycleClock::new(host_time())
+    }
+}
+
+// from the epoch, for runs which have to be reproduced like movies
+impl Default for CycleClock {
+    fn default() -> Self {
+        CycleClock::new(0)
+    }
+}
+
+impl Clock for CycleClock {
+    fn now(&self) -> u64 {
+        self.start + self.cycles / CPU_CLOCK_HZ as u64
+    }
+
+    fn advance(&mut self, cycles: u32) -> u64 {
+        self.cycles += cycles as u64;
+        cycles as u64
+    }
+}
+
+// stands still except for the jumps of its script, each one sets the time once the cpu has run the cycles
+#[derive(Debug)]
+pub struct FixedClock {
+    time: u64,
+    cycles: u64,
+    script: Vec<(u64, u64)>,
+}
+
+impl FixedClock {
+    pub fn new(time: u64) -> FixedClock {
+        FixedClock {
+            time,
+            cycles: 0u64,
+            script: vec![],
+        }
+    }
+
+    pub fn then(mut self, cycles: u64, time: u64) -> FixedClock {
+        self.script.push((cycles, time));
+        self.script.sort_by_key(|(cycles, _)| *cycles);
+        self
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.time
+    }
+
+    // the clock can only be set forward, the rtc does not run backwards
+    fn advance(&mut self, cycles: u32) -> u64 {
+        self.cycles += cycles as u64;
+        let mut seconds = 0;
+        while let Some(&(at, time)) = self.script.first() {
+            if at > self.cycles {
+                break;
+            }
+            seconds += time.saturating_sub(self.time);
+            self.time = self.time.max(time);
+            self.script.remove(0);
+        }
+        seconds * CPU_CLOCK_HZ as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::cartridge::mbc3::RTC_M;
+    use crate::cartridge::mbc3::Mbc3;
+    use crate::cartridge::Cartridge;
+    use crate::mem::ram::Ram;
+    use crate::mem::rom::Rom;
+
+    #[test]
+    fn test_cycle_and_fixed_clock() {
+        let mut clock = CycleClock::new(100);
+        assert_eq!(clock.advance(CPU_CLOCK_HZ), CPU_CLOCK_HZ as u64);
+        assert_eq!(clock.now(), 101);
+
+        let mut clock = FixedClock::new(100).then(10, 160).then(5, 130);
+        assert_eq!(clock.advance(4), 0);
+        assert_eq!(clock.advance(1), 30 * CPU_CLOCK_HZ as u64);
+        assert_eq!(clock.now(), 130);
+        assert_eq!(clock.advance(10), 30 * CPU_CLOCK_HZ as u64);
+        assert_eq!(clock.advance(CPU_CLOCK_HZ), 0);
+        assert_eq!(clock.now(), 160);
+    }
+
+    #[test]
+    fn test_cartridge_clock() {
+        let mut rom = Rom::new(test_rom(0x10, 0x00, 0x02));
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        cart.set_clock(FixedClock::new(1000).then(1, 1120));
+        cart.tick(CPU_CLOCK_HZ);
+        let mbc = cart.mbc_as::<Mbc3>().unwrap();
+        assert_eq!(mbc.rtc().unwrap().register(RTC_M), 2);
+        assert_eq!(cart.clock().now(), 1120);
+
+        // the default clock starts at the time of the host
+        let mut rom = Rom::new(test_rom(0x10, 0x00, 0x02));
+        let mut ram = Ram::new(vec![]);
+        let cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        assert!(cart.clock().now() >= host_time() - 1);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::*;
+use crate::mem::rom::Rom;
+use super::save::write_atomic;
+
+const SIDECAR_EXTENSION: &str = "flash";
+
+const UNLOCK1_DATA: u8 = 0xaa;
+const UNLOCK2_DATA: u8 = 0x55;
+const COMMAND_ID: u8 = 0x90;
+const COMMAND_PROGRAM: u8 = 0xa0;
+const COMMAND_ERASE: u8 = 0x80;
+const COMMAND_RESET: u8 = 0xf0;
+const ERASE_SECTOR: u8 = 0x30;
+const ERASE_CHIP: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashChip {
+    pub manufacturer: u8,
+    pub device: u8,
+    pub sector_size: usize,
+}
+
+impl FlashChip {
+    pub const AM29F016B: FlashChip = FlashChip { manufacturer: 0x01, device: 0xad, sector_size: 0x10000 };
+    pub const MX29LV640: FlashChip = FlashChip { manufacturer: 0xc2, device: 0xc9, sector_size: 0x10000 };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Read,
+    Unlock1,
+    Unlock2,
+    Program,
+    Erase,
+    EraseUnlock1,
+    EraseUnlock2,
+}
+
+// an AMD compatible flash chip in place of the rom, it sees every write to 0x0000-0x7fff
+// together with the mbc, so the command addresses are checked on their lower bits only.
+// programming and erasing finish at once, so polling the status always sees the final data
+#[derive(Debug)]
+pub struct Flash {
+    chip: FlashChip,
+    state: State,
+    id_mode: bool,
+    dirty: bool,
+}
+
+impl Flash {
+    pub fn new(chip: FlashChip) -> Flash {
+        Flash {
+            chip,
+            state: State::Read,
+            id_mode: false,
+            dirty: false,
+        }
+    }
+
+    pub fn chip(&self) -> FlashChip {
+        self.chip
+    }
+
+    // true once after the rom has been programmed or erased
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.dirty, false)
+    }
+
+    // Some while the chip answers with its id instead of the data
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        if !self.id_mode {
+            return None;
+        }
+        match addr & 0xff {
+            0x00 => Some(self.chip.manufacturer),
+            0x01 => Some(self.chip.device),
+            _ => Some(0x00),
+        }
+    }
+
+    // addr is the cpu address and offset the rom offset it is mapped to
+    pub fn write(&mut self, rom: &mut Rom, addr: u16, offset: usize, val: u8) {
+        let unlock1 = matches!(addr & 0x0fff, 0x555 | 0xaaa) && val == UNLOCK1_DATA;
+        let unlock2 = matches!(addr & 0x0fff, 0x2aa | 0x555 | 0xaaa) && val == UNLOCK2_DATA;
+        if val == COMMAND_RESET && self.state != State::Program {
+            self.state = State::Read;
+            self.id_mode = false;
+            return;
+        }
+        self.state = match self.state {
+            State::Read if unlock1 => State::Unlock1,
+            State::Unlock1 if unlock2 => State::Unlock2,
+            State::Unlock2 => match val {
+                COMMAND_ID => {
+                    self.id_mode = true;
+                    State::Read
+                },
+                COMMAND_PROGRAM => State::Program,
+                COMMAND_ERASE => State::Erase,
+                _ => State::Read,
+            },
+            State::Program => {
+                // programming can only clear bits
+                if let Some(old) = rom.get(offset) {
+                    rom.set(offset, old & val);
+                    self.dirty = true;
+                }
+                State::Read
+            },
+            State::Erase if unlock1 => State::EraseUnlock1,
+            State::EraseUnlock1 if unlock2 => State::EraseUnlock2,
+            State::EraseUnlock2 => {
+                let range = match val {
+                    ERASE_SECTOR => {
+                        let top = offset / self.chip.sector_size * self.chip.sector_size;
+                        top..(top + self.chip.sector_size).min(rom.len())
+                    },
+                    ERASE_CHIP => 0..rom.len(),
+                    _ => 0..0,
+                };
+                for offset in range {
+                    rom.set(offset, 0xff);
+                    self.dirty = true;
+                }
+                State::Read
+            },
+            _ => State::Read,
+        };
+    }
+}
+
+pub fn sidecar_path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension(SIDECAR_EXTENSION)
+}
+
+// the flash contents go back to the rom file itself or to a sidecar next to it
+pub fn save(rom: &Rom, path: &Path) -> GBResult<()> {
+    write_atomic(path, rom.data())
+}
+
+// replaces the rom with a sidecar of the same size, false when there is none
+pub fn load(rom: &mut Rom, path: &Path) -> GBResult<bool> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if data.len() != rom.len() {
+        return Err(GBError::InvalidData);
+    }
+    for (offset, val) in data.into_iter().enumerate() {
+        rom.set(offset, val);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::cartridge::Cartridge;
+    use crate::device::Device;
+    use crate::mem::ram::Ram;
+
+    fn command(cart: &mut Cartridge, val: u8) {
+        cart.write(0x0555, UNLOCK1_DATA).unwrap();
+        cart.write(0x02aa, UNLOCK2_DATA).unwrap();
+        cart.write(0x0555, val).unwrap();
+    }
+
+    #[test]
+    fn test_flash() {
+        let mut v = test_rom(0x1b, 0x02, 0x02);
+        v[0x10000] = 0xf0;
+        let mut rom = Rom::new(v);
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        cart.set_flash(Some(FlashChip::AM29F016B));
+
+        command(&mut cart, COMMAND_ID);
+        assert_eq!((cart.read(0x0000).unwrap(), cart.read(0x0001).unwrap()), (0x01, 0xad));
+        cart.write(0x0000, COMMAND_RESET).unwrap();
+        assert_eq!(cart.read(0x0000).unwrap(), 0x00);
+
+        // program a byte in bank 4, the mbc sees the same writes
+        cart.write(0x2000, 0x04).unwrap();
+        command(&mut cart, COMMAND_PROGRAM);
+        cart.write(0x4000, 0x3c).unwrap();
+        assert_eq!(cart.read(0x4000).unwrap(), 0x30);
+        assert!(cart.take_flash_dirty());
+        assert!(!cart.take_flash_dirty());
+
+        // a plain write does not program
+        cart.write(0x4000, 0x00).unwrap();
+        assert_eq!(cart.read(0x4000).unwrap(), 0x30);
+
+        command(&mut cart, COMMAND_ERASE);
+        cart.write(0x0555, UNLOCK1_DATA).unwrap();
+        cart.write(0x02aa, UNLOCK2_DATA).unwrap();
+        cart.write(0x4000, ERASE_SECTOR).unwrap();
+        assert_eq!(cart.read(0x4000).unwrap(), 0xff);
+        assert_eq!(cart.rom().get(0x0000), Some(0x00));
+        assert_eq!(cart.rom().get(0x1ffff), Some(0xff));
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let dir = std::env::temp_dir().join(format!("rusgb-flash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = sidecar_path(&dir.join("game.gb"));
+        assert_eq!(path, dir.join("game.flash"));
+
+        let mut rom = Rom::new(vec![0u8; 0x10]);
+        assert!(!load(&mut rom, &path).unwrap());
+        save(&Rom::new(vec![0x5au8; 0x10]), &path).unwrap();
+        assert!(load(&mut rom, &path).unwrap());
+        assert_eq!(rom.get(0x0f), Some(0x5a));
+        assert!(matches!(load(&mut Rom::new(vec![0u8; 0x20]), &path), Err(GBError::InvalidData)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
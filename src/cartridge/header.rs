@@ -0,0 +1,348 @@
+use crate::error::*;
+use crate::mem::rom::Rom;
+
+pub const LOGO_ADDR: usize = 0x0104;
+pub const TITLE_ADDR: usize = 0x0134;
+pub const MANUFACTURER_CODE_ADDR: usize = 0x013f;
+pub const CGB_FLAG_ADDR: usize = 0x0143;
+pub const NEW_LICENSEE_CODE_ADDR: usize = 0x0144;
+pub const SGB_FLAG_ADDR: usize = 0x0146;
+pub const CARTRIDGE_TYPE_ADDR: usize = 0x0147;
+pub const ROM_SIZE_ADDR: usize = 0x0148;
+pub const RAM_SIZE_ADDR: usize = 0x0149;
+pub const DESTINATION_CODE_ADDR: usize = 0x014a;
+pub const OLD_LICENSEE_CODE_ADDR: usize = 0x014b;
+pub const VERSION_ADDR: usize = 0x014c;
+pub const HEADER_CHECKSUM_ADDR: usize = 0x014d;
+pub const GLOBAL_CHECKSUM_ADDR: usize = 0x014e;
+pub const HEADER_TAIL: usize = 0x014f;
+
+pub const NINTENDO_LOGO: [u8; 0x30] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+// the old licensee code which means the new licensee code is used instead
+const USE_NEW_LICENSEE: u8 = 0x33;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    Mmm01,
+    PocketCamera,
+    Tama5,
+    HuC1,
+    HuC3,
+    // unlicensed
+    WisdomTree,
+    SachenMmc1,
+    SachenMmc2,
+    M161,
+    Bbd,
+    Hitek,
+    // a mapper from another crate
+    Custom,
+}
+
+impl MbcKind {
+    pub fn is_unlicensed(&self) -> bool {
+        use MbcKind::*;
+        matches!(self, WisdomTree | SachenMmc1 | SachenMmc2 | M161 | Bbd | Hitek)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mbc: MbcKind,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> GBResult<CartridgeType> {
+        use MbcKind::*;
+        // (mbc, ram, battery, timer, rumble)
+        let (mbc, ram, battery, timer, rumble) = match code {
+            0x00 => (None, false, false, false, false),
+            0x01 => (Mbc1, false, false, false, false),
+            0x02 => (Mbc1, true, false, false, false),
+            0x03 => (Mbc1, true, true, false, false),
+            0x05 => (Mbc2, false, false, false, false),
+            0x06 => (Mbc2, false, true, false, false),
+            0x08 => (None, true, false, false, false),
+            0x09 => (None, true, true, false, false),
+            0x0b => (Mmm01, false, false, false, false),
+            0x0c => (Mmm01, true, false, false, false),
+            0x0d => (Mmm01, true, true, false, false),
+            0x0f => (Mbc3, false, true, true, false),
+            0x10 => (Mbc3, true, true, true, false),
+            0x11 => (Mbc3, false, false, false, false),
+            0x12 => (Mbc3, true, false, false, false),
+            0x13 => (Mbc3, true, true, false, false),
+            0x19 => (Mbc5, false, false, false, false),
+            0x1a => (Mbc5, true, false, false, false),
+            0x1b => (Mbc5, true, true, false, false),
+            0x1c => (Mbc5, false, false, false, true),
+            0x1d => (Mbc5, true, false, false, true),
+            0x1e => (Mbc5, true, true, false, true),
+            0x20 => (Mbc6, true, true, false, false),
+            0x22 => (Mbc7, true, true, false, true),
+            0xfc => (PocketCamera, true, true, false, false),
+            0xfd => (Tama5, true, true, true, false),
+            0xfe => (HuC3, true, true, true, false),
+            0xff => (HuC1, true, true, false, false),
+            _ => return Err(GBError::UnsupportedCartridgeType(code)),
+        };
+        Ok(CartridgeType { code, mbc, ram, battery, timer, rumble })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbFlag {
+    Dmg,
+    Supported, // 0x80
+    Only, // 0xc0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Japanese,
+    Overseas,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: String,
+    pub cgb_flag: CgbFlag,
+    pub sgb_flag: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub old_licensee_code: u8,
+    pub new_licensee_code: String,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &Rom) -> GBResult<CartridgeHeader> {
+        CartridgeHeader::parse_as(rom, |addr| addr, None)
+    }
+
+    // map translates header addresses to rom offsets for cartridges which move or scramble their header,
+    // mbc overrides the cartridge type of unlicensed cartridges whose header lies
+    pub fn parse_as<F>(rom: &Rom, map: F, mbc: Option<MbcKind>) -> GBResult<CartridgeHeader>
+    where
+        F: Fn(usize) -> usize,
+    {
+        if rom.len() <= HEADER_TAIL || rom.len() <= map(HEADER_TAIL) {
+            return Err(GBError::HeaderTooShort(rom.len()));
+        }
+        let byte = |addr: usize| rom.get(map(addr)).unwrap_or(0);
+
+        let cgb_flag = match byte(CGB_FLAG_ADDR) {
+            0xc0 => CgbFlag::Only,
+            0x80 => CgbFlag::Supported,
+            _ => CgbFlag::Dmg,
+        };
+        // newer cartridges shorten the title to 11 bytes to hold the manufacturer code
+        let (title, manufacturer_code) = match cgb_flag {
+            CgbFlag::Dmg => (ascii(&byte, TITLE_ADDR, CGB_FLAG_ADDR + 1), String::new()),
+            _ => (ascii(&byte, TITLE_ADDR, MANUFACTURER_CODE_ADDR), ascii(&byte, MANUFACTURER_CODE_ADDR, CGB_FLAG_ADDR)),
+        };
+        let rom_size = match byte(ROM_SIZE_ADDR) {
+            n @ 0x00..=0x08 => 0x8000 << n,
+            0x52 => 72 * 0x4000,
+            0x53 => 80 * 0x4000,
+            0x54 => 96 * 0x4000,
+            _ if mbc.is_some() => rom.len(),
+            n => return Err(GBError::InvalidRomSize(n)),
+        };
+        let ram_size = match byte(RAM_SIZE_ADDR) {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            _ if mbc.is_some() => 0,
+            n => return Err(GBError::InvalidRamSize(n)),
+        };
+        let code = byte(CARTRIDGE_TYPE_ADDR);
+        let cartridge_type = match mbc {
+            Some(mbc) => match CartridgeType::from_code(code) {
+                Ok(t) => CartridgeType { mbc, ..t },
+                Err(_) => CartridgeType { code, mbc, ram: ram_size > 0, battery: false, timer: false, rumble: false },
+            },
+            None => CartridgeType::from_code(code)?,
+        };
+        let destination = match byte(DESTINATION_CODE_ADDR) {
+            0x00 => Destination::Japanese,
+            _ => Destination::Overseas,
+        };
+        let old_licensee_code = byte(OLD_LICENSEE_CODE_ADDR);
+        let new_licensee_code = match old_licensee_code {
+            USE_NEW_LICENSEE => ascii(&byte, NEW_LICENSEE_CODE_ADDR, SGB_FLAG_ADDR),
+            _ => String::new(),
+        };
+        let header = CartridgeHeader {
+            title,
+            manufacturer_code,
+            cgb_flag,
+            sgb_flag: byte(SGB_FLAG_ADDR) == 0x03,
+            cartridge_type,
+            rom_size,
+            ram_size,
+            destination,
+            old_licensee_code,
+            new_licensee_code,
+            version: byte(VERSION_ADDR),
+            header_checksum: byte(HEADER_CHECKSUM_ADDR),
+            global_checksum: u16::from_be_bytes([byte(GLOBAL_CHECKSUM_ADDR), byte(GLOBAL_CHECKSUM_ADDR + 1)]),
+        };
+        // the boot rom refuses to start a cartridge with a broken header checksum. unlicensed
+        // cartridges are loaded anyway, their header often only adds up in a view the mapper scrambles,
+        // and so are prototypes with a custom mapper whose header was never finished
+        let sum = checksum(&byte);
+        if sum != header.header_checksum && !mbc.is_some_and(|mbc| mbc.is_unlicensed() || mbc == MbcKind::Custom) {
+            return Err(GBError::HeaderChecksumMismatch(header.header_checksum, sum));
+        }
+        Ok(header)
+    }
+
+    pub fn validate_header_checksum(&self, rom: &Rom) -> GBResult<()> {
+        let sum = header_checksum(rom);
+        if sum != self.header_checksum {
+            return Err(GBError::HeaderChecksumMismatch(self.header_checksum, sum));
+        }
+        Ok(())
+    }
+
+    // the global checksum is not checked by the hardware, many homebrew roms get it wrong
+    pub fn validate_global_checksum(&self, rom: &Rom) -> GBResult<()> {
+        let sum = global_checksum(rom);
+        if sum != self.global_checksum {
+            return Err(GBError::GlobalChecksumMismatch(self.global_checksum, sum));
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self, rom: &Rom) -> GBResult<()> {
+        self.validate_header_checksum(rom)?;
+        self.validate_global_checksum(rom)
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.cgb_flag != CgbFlag::Dmg
+    }
+}
+
+fn ascii(byte: &dyn Fn(usize) -> u8, top: usize, tail: usize) -> String {
+    (top..tail)
+        .map(byte)
+        .take_while(|b| *b != 0)
+        .filter(|b| b.is_ascii_graphic() || *b == b' ')
+        .map(|b| b as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+fn checksum(byte: &dyn Fn(usize) -> u8) -> u8 {
+    (TITLE_ADDR..HEADER_CHECKSUM_ADDR).fold(0u8, |x, addr| x.wrapping_sub(byte(addr)).wrapping_sub(1))
+}
+
+pub fn header_checksum(rom: &Rom) -> u8 {
+    checksum(&|addr| rom.get(addr).unwrap_or(0))
+}
+
+pub fn global_checksum(rom: &Rom) -> u16 {
+    (0..rom.len())
+        .filter(|addr| *addr != GLOBAL_CHECKSUM_ADDR && *addr != GLOBAL_CHECKSUM_ADDR + 1)
+        .fold(0u16, |x, addr| x.wrapping_add(rom.get(addr).unwrap_or(0) as u16))
+}
+
+// builds a rom image with a valid header for tests
+#[cfg(test)]
+pub fn test_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+    let mut v = vec![0u8; 0x8000 << rom_size];
+    v[TITLE_ADDR..TITLE_ADDR + 4].copy_from_slice(b"TEST");
+    v[CARTRIDGE_TYPE_ADDR] = cartridge_type;
+    v[ROM_SIZE_ADDR] = rom_size;
+    v[RAM_SIZE_ADDR] = ram_size;
+    fix_checksums(&mut v);
+    v
+}
+
+#[cfg(test)]
+pub fn fix_checksums(v: &mut [u8]) {
+    v[HEADER_CHECKSUM_ADDR] = header_checksum(&Rom::new(v.to_vec()));
+    let [hi, lo] = global_checksum(&Rom::new(v.to_vec())).to_be_bytes();
+    v[GLOBAL_CHECKSUM_ADDR] = hi;
+    v[GLOBAL_CHECKSUM_ADDR + 1] = lo;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let mut v = test_rom(0x13, 0x05, 0x03);
+        v[TITLE_ADDR..TITLE_ADDR + 11].copy_from_slice(b"POKEMON_SLV");
+        v[MANUFACTURER_CODE_ADDR..CGB_FLAG_ADDR].copy_from_slice(b"AAXE");
+        v[CGB_FLAG_ADDR] = 0x80;
+        v[NEW_LICENSEE_CODE_ADDR..SGB_FLAG_ADDR].copy_from_slice(b"01");
+        v[SGB_FLAG_ADDR] = 0x03;
+        v[DESTINATION_CODE_ADDR] = 0x01;
+        v[OLD_LICENSEE_CODE_ADDR] = 0x33;
+        v[VERSION_ADDR] = 0x01;
+        fix_checksums(&mut v);
+        let rom = Rom::new(v);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "POKEMON_SLV");
+        assert_eq!(header.manufacturer_code, "AAXE");
+        assert_eq!(header.cgb_flag, CgbFlag::Supported);
+        assert!(header.sgb_flag);
+        assert_eq!(header.cartridge_type.mbc, MbcKind::Mbc3);
+        assert!(header.cartridge_type.battery);
+        assert!(!header.cartridge_type.timer);
+        assert_eq!(header.rom_size, 0x100000);
+        assert_eq!(header.ram_size, 0x8000);
+        assert_eq!(header.destination, Destination::Overseas);
+        assert_eq!(header.new_licensee_code, "01");
+        assert_eq!(header.version, 1);
+        assert!(header.validate(&rom).is_ok());
+    }
+
+    #[test]
+    fn test_parse_header_error() {
+        assert!(matches!(CartridgeHeader::parse(&Rom::new(vec![0u8; 0x100])), Err(GBError::HeaderTooShort(0x100))));
+
+        let mut v = test_rom(0x00, 0x00, 0x00);
+        v[HEADER_CHECKSUM_ADDR] ^= 0xff;
+        assert!(matches!(CartridgeHeader::parse(&Rom::new(v)), Err(GBError::HeaderChecksumMismatch(..))));
+
+        let v = test_rom(0x04, 0x00, 0x00);
+        assert!(matches!(CartridgeHeader::parse(&Rom::new(v)), Err(GBError::UnsupportedCartridgeType(0x04))));
+
+        let mut v = test_rom(0x00, 0x00, 0x00);
+        v[0x0150] = 0xff;
+        let rom = Rom::new(v);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(matches!(header.validate(&rom), Err(GBError::GlobalChecksumMismatch(..))));
+    }
+}
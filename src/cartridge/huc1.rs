@@ -0,0 +1,116 @@
+use crate::mem::*;
+use super::*;
+
+const IR_MODE: u8 = 0x0e;
+
+#[derive(Debug)]
+pub struct HuC1 {
+    ir_mode: bool, // 0xa000-0xbfff is the ir port instead of ram
+    rom_bank: u8,
+    ram_bank: u8,
+    ir_led: bool,
+    ir_input: bool,
+}
+
+impl HuC1 {
+    pub fn new() -> HuC1 {
+        HuC1 {
+            ir_mode: false,
+            rom_bank: 1u8,
+            ram_bank: 0u8,
+            ir_led: false,
+            ir_input: false,
+        }
+    }
+
+    pub fn read_ir(&self) -> Option<u8> {
+        if !self.ir_mode {
+            return None;
+        }
+        Some(0xc0 | self.ir_input as u8)
+    }
+
+    pub fn write_ir(&mut self, val: u8) -> bool {
+        if !self.ir_mode {
+            return false;
+        }
+        self.ir_led = val & 0x01 != 0;
+        true
+    }
+}
+
+impl Mapper for HuC1 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => addr as usize,
+            _ => self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - ROM_BANK_ADDR_TOP),
+        }
+    }
+
+    // HuC1 has no ram enable, the ram is always mapped unless the ir port is selected
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if self.ir_mode {
+            return None;
+        }
+        Some(self.ram_bank as usize * RAM_BANK_SIZE + addr as usize - EXTERNAL_RAM_ADDR_TOP)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ir_mode = val & 0x0f == IR_MODE,
+            0x2000..=0x3fff => self.rom_bank = val & 0x3f,
+            0x4000..=0x5fff => self.ram_bank = val & 0x03,
+            _ => {},
+        }
+    }
+
+    fn ir_led(&self) -> bool {
+        self.ir_led
+    }
+
+    // true while the sensor sees light, e.g. the led of another cartridge
+    fn set_ir_input(&mut self, light: bool) {
+        self.ir_input = light;
+    }
+
+    fn read_ram(&self, ram: &Ram, addr: u16) -> u8 {
+        self.read_ir().unwrap_or_else(|| read_mapped_ram(self, ram, addr))
+    }
+
+    fn write_ram(&mut self, ram: &mut Ram, addr: u16, val: u8) {
+        if !self.write_ir(val) {
+            write_mapped_ram(self, ram, addr, val);
+        }
+    }
+}
+
+impl Default for HuC1 {
+    fn default() -> Self {
+        HuC1::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_huc1_ir() {
+        let mut mbc = HuC1::new();
+        mbc.write_register(0x4000, 0x02);
+        assert_eq!(mbc.ram_offset(0xa000), Some(2 * RAM_BANK_SIZE));
+        assert_eq!(mbc.read_ir(), None);
+
+        mbc.write_register(0x0000, 0x0e);
+        assert_eq!(mbc.ram_offset(0xa000), None);
+        assert_eq!(mbc.read_ir(), Some(0xc0));
+        mbc.set_ir_input(true);
+        assert_eq!(mbc.read_ir(), Some(0xc1));
+        assert!(mbc.write_ir(0x01));
+        assert!(mbc.ir_led());
+
+        mbc.write_register(0x0000, 0x0a);
+        assert!(!mbc.write_ir(0x00));
+        assert!(mbc.ir_led());
+    }
+}
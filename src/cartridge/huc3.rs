@@ -0,0 +1,371 @@
+use crate::mem::*;
+use super::*;
+use super::mbc3::CPU_CLOCK_HZ;
+
+const MODE_RAM_READ: u8 = 0x00;
+const MODE_RAM: u8 = 0x0a;
+const MODE_RTC_COMMAND: u8 = 0x0b;
+const MODE_RTC_RESPONSE: u8 = 0x0c;
+const MODE_RTC_SEMAPHORE: u8 = 0x0d;
+const MODE_IR: u8 = 0x0e;
+
+// rtc commands in the upper nibble of the command register
+const COMMAND_READ: u8 = 0x1;
+const COMMAND_WRITE: u8 = 0x3;
+const COMMAND_ADDR_LOW: u8 = 0x4;
+const COMMAND_ADDR_HIGH: u8 = 0x5;
+const COMMAND_EXTENDED: u8 = 0x6;
+
+// arguments of the extended command
+const EXTENDED_READ_TIME: u8 = 0x0;
+const EXTENDED_WRITE_TIME: u8 = 0x1;
+const EXTENDED_STATUS: u8 = 0x2;
+const EXTENDED_TONE: u8 = 0xe;
+
+// the time is copied to 3 nibbles of minutes and 4 nibbles of days
+const TIME_ADDR: usize = 0x00;
+const TONE_ADDR: usize = 0x26;
+pub const MINUTES_PER_DAY: u16 = 60 * 24;
+const CYCLES_PER_MINUTE: u64 = CPU_CLOCK_HZ as u64 * 60;
+
+// the HuC3 footer of SameBoy and mGBA, the unix time of the save as 64bit, minutes and days as 16bit,
+// then the alarm minutes, days and enable which are not emulated
+pub const RTC_FOOTER_SIZE: usize = 17;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuC3Rtc {
+    pub minutes: u16, // minute of the day
+    pub days: u16,
+    cycles: u64, // sub minute counter
+}
+
+impl HuC3Rtc {
+    pub fn new() -> HuC3Rtc {
+        HuC3Rtc {
+            minutes: 0u16,
+            days: 0u16,
+            cycles: 0u64,
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        if self.cycles >= CYCLES_PER_MINUTE {
+            self.advance_minutes(self.cycles / CYCLES_PER_MINUTE);
+            self.cycles %= CYCLES_PER_MINUTE;
+        }
+    }
+
+    pub fn advance_minutes(&mut self, minutes: u64) {
+        let total = self.minutes as u64 + minutes;
+        self.minutes = (total % MINUTES_PER_DAY as u64) as u16;
+        self.days = self.days.wrapping_add((total / MINUTES_PER_DAY as u64) as u16);
+    }
+
+    pub fn footer(&self, now: u64) -> Vec<u8> {
+        let mut v = Vec::with_capacity(RTC_FOOTER_SIZE);
+        v.extend_from_slice(&now.to_le_bytes());
+        v.extend_from_slice(&self.minutes.to_le_bytes());
+        v.extend_from_slice(&self.days.to_le_bytes());
+        v.resize(RTC_FOOTER_SIZE, 0u8);
+        v
+    }
+
+    // the 17 byte footer sets the minutes and days, the alarm in its last bytes is dropped and
+    // the minutes since the stamp are added. false for any other size
+    pub fn load_footer(&mut self, data: &[u8], now: u64) -> bool {
+        if data.len() != RTC_FOOTER_SIZE {
+            return false;
+        }
+        let saved = u64::from_le_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]]);
+        self.minutes = u16::from_le_bytes([data[8], data[9]]) % MINUTES_PER_DAY;
+        self.days = u16::from_le_bytes([data[10], data[11]]);
+        self.cycles = 0;
+        let seconds = now.saturating_sub(saved);
+        self.advance_minutes(seconds / 60);
+        self.tick(seconds % 60 * CPU_CLOCK_HZ as u64);
+        true
+    }
+}
+
+impl Default for HuC3Rtc {
+    fn default() -> Self {
+        HuC3Rtc::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct HuC3 {
+    mode: u8,
+    rom_bank: u8,
+    ram_bank: u8,
+    rtc: HuC3Rtc,
+    memory: Vec<u8>, // 256 x 4bit rtc memory
+    addr: u8,
+    command: u8,
+    result: u8,
+    ready: bool,
+    tone: Option<u8>,
+    ir_led: bool,
+    ir_input: bool,
+    rtc_changed: bool,
+}
+
+impl HuC3 {
+    pub fn new() -> HuC3 {
+        HuC3 {
+            mode: MODE_RAM_READ,
+            rom_bank: 1u8,
+            ram_bank: 0u8,
+            rtc: HuC3Rtc::new(),
+            memory: vec![0u8; 0x100],
+            addr: 0u8,
+            command: 0u8,
+            result: 0u8,
+            ready: true,
+            tone: None,
+            ir_led: false,
+            ir_input: false,
+            rtc_changed: false,
+        }
+    }
+
+    pub fn rtc(&self) -> &HuC3Rtc {
+        &self.rtc
+    }
+
+    pub fn rtc_mut(&mut self) -> &mut HuC3Rtc {
+        &mut self.rtc
+    }
+
+    pub fn rtc_footer(&self, now: u64) -> Vec<u8> {
+        self.rtc.footer(now)
+    }
+
+    pub fn load_rtc_footer(&mut self, data: &[u8], now: u64) -> bool {
+        self.rtc.load_footer(data, now)
+    }
+
+    // the last tone the speaker was asked to play
+    pub fn take_tone(&mut self) -> Option<u8> {
+        self.tone.take()
+    }
+
+    pub fn ram_writable(&self) -> bool {
+        self.mode == MODE_RAM
+    }
+
+    // Some when a rtc or ir register is mapped to 0xa000-0xbfff
+    pub fn read_port(&self) -> Option<u8> {
+        match self.mode {
+            MODE_RTC_RESPONSE => Some(0x80 | (self.command & 0x70) | self.result),
+            MODE_RTC_SEMAPHORE => Some(0xfe | self.ready as u8),
+            MODE_IR => Some(0xc0 | self.ir_input as u8),
+            MODE_RAM_READ | MODE_RAM => None,
+            _ => Some(0xff),
+        }
+    }
+
+    pub fn write_port(&mut self, val: u8) -> bool {
+        match self.mode {
+            MODE_RTC_COMMAND => {
+                self.command = val & 0x7f;
+                self.ready = false;
+            },
+            // writing 0 to the semaphore runs the command
+            MODE_RTC_SEMAPHORE if val & 0x01 == 0 => {
+                self.execute();
+                self.ready = true;
+            },
+            MODE_IR => self.ir_led = val & 0x01 != 0,
+            MODE_RAM_READ | MODE_RAM => return false,
+            _ => {},
+        }
+        true
+    }
+
+    fn execute(&mut self) {
+        let arg = self.command & 0x0f;
+        match self.command >> 4 {
+            COMMAND_READ => {
+                self.result = self.memory[self.addr as usize] & 0x0f;
+                self.addr = self.addr.wrapping_add(1);
+            },
+            COMMAND_WRITE => {
+                self.memory[self.addr as usize] = arg;
+                self.addr = self.addr.wrapping_add(1);
+            },
+            COMMAND_ADDR_LOW => self.addr = (self.addr & 0xf0) | arg,
+            COMMAND_ADDR_HIGH => self.addr = (self.addr & 0x0f) | (arg << 4),
+            COMMAND_EXTENDED => match arg {
+                EXTENDED_READ_TIME => {
+                    let time = self.rtc.minutes as u32 | ((self.rtc.days as u32) << 12);
+                    for i in 0..7 {
+                        self.memory[TIME_ADDR + i] = ((time >> (i * 4)) & 0x0f) as u8;
+                    }
+                },
+                EXTENDED_WRITE_TIME => {
+                    let time = (0..7).fold(0u32, |time, i| time | ((self.memory[TIME_ADDR + i] as u32 & 0x0f) << (i * 4)));
+                    self.rtc.minutes = (time & 0xfff) as u16 % MINUTES_PER_DAY;
+                    self.rtc.days = (time >> 12) as u16;
+                    self.rtc_changed = true;
+                },
+                EXTENDED_STATUS => self.result = 0x01,
+                EXTENDED_TONE => self.tone = Some(self.memory[TONE_ADDR] & 0x0f),
+                _ => {},
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Mapper for HuC3 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => addr as usize,
+            _ => self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - ROM_BANK_ADDR_TOP),
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        match self.mode {
+            MODE_RAM_READ | MODE_RAM => Some(self.ram_bank as usize * RAM_BANK_SIZE + addr as usize - EXTERNAL_RAM_ADDR_TOP),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.mode = val & 0x0f,
+            0x2000..=0x3fff => self.rom_bank = val & 0x7f,
+            0x4000..=0x5fff => self.ram_bank = val & 0x03,
+            _ => {},
+        }
+    }
+
+    fn ir_led(&self) -> bool {
+        self.ir_led
+    }
+
+    fn set_ir_input(&mut self, light: bool) {
+        self.ir_input = light;
+    }
+
+    fn tick_rtc(&mut self, cycles: u64) {
+        self.rtc.tick(cycles);
+    }
+
+    fn take_clock_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.rtc_changed, false)
+    }
+
+    fn read_ram(&self, ram: &Ram, addr: u16) -> u8 {
+        self.read_port().unwrap_or_else(|| read_mapped_ram(self, ram, addr))
+    }
+
+    fn write_ram(&mut self, ram: &mut Ram, addr: u16, val: u8) {
+        if !self.write_port(val) && self.ram_writable() {
+            write_mapped_ram(self, ram, addr, val);
+        }
+    }
+
+    fn save_data(&self, ram: &Ram, now: u64) -> Vec<u8> {
+        let mut v = ram.data().to_vec();
+        v.extend(self.rtc_footer(now));
+        v
+    }
+
+    fn load_save_data(&mut self, ram: &mut Ram, data: &[u8], now: u64) {
+        let footer = load_ram(ram, data);
+        if !footer.is_empty() {
+            self.load_rtc_footer(footer, now);
+        }
+    }
+}
+
+impl Default for HuC3 {
+    fn default() -> Self {
+        HuC3::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(mbc: &mut HuC3, command: u8) -> u8 {
+        mbc.write_register(0x0000, MODE_RTC_COMMAND);
+        mbc.write_port(command);
+        mbc.write_register(0x0000, MODE_RTC_SEMAPHORE);
+        mbc.write_port(0x00);
+        assert_eq!(mbc.read_port(), Some(0xff));
+        mbc.write_register(0x0000, MODE_RTC_RESPONSE);
+        mbc.read_port().unwrap() & 0x0f
+    }
+
+    #[test]
+    fn test_huc3_rtc() {
+        let mut mbc = HuC3::new();
+        mbc.rtc_mut().advance_minutes(MINUTES_PER_DAY as u64 * 3 + 0x123);
+        command(&mut mbc, 0x60);
+        command(&mut mbc, 0x40);
+        command(&mut mbc, 0x50);
+        let nibbles: Vec<u8> = (0..7).map(|_| command(&mut mbc, 0x10)).collect();
+        assert_eq!(nibbles, vec![0x3, 0x2, 0x1, 0x3, 0x0, 0x0, 0x0]);
+
+        // write 2 days and 5 minutes back to the clock
+        command(&mut mbc, 0x40);
+        for n in [0x5, 0x0, 0x0, 0x2, 0x0, 0x0, 0x0].iter() {
+            command(&mut mbc, 0x30 | n);
+        }
+        command(&mut mbc, 0x61);
+        assert_eq!((mbc.rtc().minutes, mbc.rtc().days), (5, 2));
+
+        mbc.tick_rtc(CPU_CLOCK_HZ as u64 * 60);
+        assert_eq!(mbc.rtc().minutes, 6);
+    }
+
+    #[test]
+    fn test_huc3_rtc_footer() {
+        let mut rtc = HuC3Rtc::new();
+        rtc.advance_minutes(MINUTES_PER_DAY as u64 + 10);
+        let footer = rtc.footer(600);
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+
+        let mut loaded = HuC3Rtc::new();
+        assert!(loaded.load_footer(&footer, 600 + 24 * 3600 + 90));
+        assert_eq!((loaded.minutes, loaded.days), (11, 2));
+        loaded.tick(CPU_CLOCK_HZ as u64 * 30);
+        assert_eq!(loaded.minutes, 12);
+        assert!(!loaded.load_footer(&footer[..16], 0));
+    }
+
+    #[test]
+    fn test_huc3_ram_and_ir() {
+        let mut mbc = HuC3::new();
+        assert_eq!(mbc.read_port(), None);
+        assert!(!mbc.ram_writable());
+        mbc.write_register(0x0000, MODE_RAM);
+        mbc.write_register(0x4000, 0x01);
+        assert_eq!(mbc.ram_offset(0xa001), Some(RAM_BANK_SIZE + 1));
+        assert!(mbc.ram_writable());
+
+        mbc.write_register(0x0000, MODE_IR);
+        assert_eq!(mbc.ram_offset(0xa001), None);
+        mbc.set_ir_input(true);
+        assert_eq!(mbc.read_port(), Some(0xc1));
+        mbc.write_port(0x01);
+        assert!(mbc.ir_led());
+    }
+
+    #[test]
+    fn test_huc3_tone() {
+        let mut mbc = HuC3::new();
+        command(&mut mbc, 0x46);
+        command(&mut mbc, 0x52);
+        command(&mut mbc, 0x37);
+        command(&mut mbc, 0x6e);
+        assert_eq!(mbc.take_tone(), Some(0x7));
+        assert_eq!(mbc.take_tone(), None);
+    }
+}
@@ -0,0 +1,178 @@
+use std::any::Any;
+use std::fmt;
+
+use crate::mem::ram::Ram;
+use crate::mem::rom::Rom;
+use crate::mem::*;
+
+// the banking hardware of a cartridge. the built-in mbcs implement it, and so can mappers
+// from other crates, which are put in a cartridge with Cartridge::with_mapper
+pub trait Mapper: Any + fmt::Debug {
+    // offset in the rom of the byte mapped at addr in 0x0000-0x7fff
+    fn rom_offset(&self, addr: u16) -> usize;
+
+    fn write_register(&mut self, addr: u16, val: u8);
+
+    // offset in the ram of the byte mapped at addr in 0xa000-0xbfff, None when the ram is disabled
+    fn ram_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    // ram built in the mapper, which the header does not count
+    fn ram_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn read_rom(&self, rom: &Rom, addr: u16) -> u8 {
+        read_mapped_rom(self, rom, addr)
+    }
+
+    // a read of the cpu, for mappers which react to reads. peek reads with read_rom
+    fn read_rom_mut(&mut self, rom: &Rom, addr: u16) -> u8 {
+        self.read_rom(rom, addr)
+    }
+
+    // disabled or unconnected ram reads as open bus
+    fn read_ram(&self, ram: &Ram, addr: u16) -> u8 {
+        read_mapped_ram(self, ram, addr)
+    }
+
+    fn write_ram(&mut self, ram: &mut Ram, addr: u16, val: u8) {
+        write_mapped_ram(self, ram, addr, val);
+    }
+
+    // cycles of the cpu
+    fn tick(&mut self, _ram: &mut Ram, _cycles: u32) {}
+
+    // cycles of the rtc, which the clock of the cartridge derives from the cpu cycles
+    fn tick_rtc(&mut self, _cycles: u64) {}
+
+    // true once after the game set or latched the clock, which the save keeps along with the ram
+    fn take_clock_changed(&mut self) -> bool {
+        false
+    }
+
+    // contents of a .sav file, now is the unix time in seconds of the clock of the cartridge
+    fn save_data(&self, ram: &Ram, _now: u64) -> Vec<u8> {
+        ram.data().to_vec()
+    }
+
+    fn load_save_data(&mut self, ram: &mut Ram, data: &[u8], _now: u64) {
+        load_ram(ram, data);
+    }
+
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    fn ir_led(&self) -> bool {
+        false
+    }
+
+    fn set_ir_input(&mut self, _light: bool) {}
+}
+
+// the no mbc cartridge with 32KB of rom and up to 8KB of ram
+#[derive(Debug, Default)]
+pub struct RomOnly;
+
+impl Mapper for RomOnly {
+    fn rom_offset(&self, addr: u16) -> usize {
+        addr as usize
+    }
+
+    fn write_register(&mut self, _addr: u16, _val: u8) {}
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        Some(addr as usize - EXTERNAL_RAM_ADDR_TOP)
+    }
+}
+
+pub fn read_mapped_rom<M: Mapper + ?Sized>(mbc: &M, rom: &Rom, addr: u16) -> u8 {
+    rom.get(mbc.rom_offset(addr) % rom.len().max(1)).unwrap_or(0xff)
+}
+
+pub fn read_mapped_ram<M: Mapper + ?Sized>(mbc: &M, ram: &Ram, addr: u16) -> u8 {
+    match mbc.ram_offset(addr) {
+        Some(offset) if !ram.is_empty() => ram.get(offset % ram.len()).unwrap_or(0xff),
+        _ => 0xff,
+    }
+}
+
+pub fn write_mapped_ram<M: Mapper + ?Sized>(mbc: &M, ram: &mut Ram, addr: u16, val: u8) {
+    if let Some(offset) = mbc.ram_offset(addr) {
+        if !ram.is_empty() {
+            ram.set(offset % ram.len(), val);
+        }
+    }
+}
+
+// saves of a different size are loaded as far as they fit, the rest is returned
+pub fn load_ram<'d>(ram: &mut Ram, data: &'d [u8]) -> &'d [u8] {
+    for (offset, val) in data.iter().take(ram.len()).enumerate() {
+        ram.set(offset, *val);
+    }
+    &data[data.len().min(ram.len())..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::cartridge::Cartridge;
+    use crate::device::Device;
+
+    // a 32KB bank at 0x0000-0x7fff selected by writes to 0x7000, with a counter saved after the ram
+    #[derive(Debug, Default)]
+    struct Prototype {
+        bank: u8,
+        cycles: u32,
+    }
+
+    impl Mapper for Prototype {
+        fn rom_offset(&self, addr: u16) -> usize {
+            self.bank as usize * 0x8000 + addr as usize
+        }
+
+        fn write_register(&mut self, addr: u16, val: u8) {
+            if addr == 0x7000 {
+                self.bank = val & 0x01;
+            }
+        }
+
+        fn tick(&mut self, _ram: &mut Ram, cycles: u32) {
+            self.cycles += cycles;
+        }
+
+        fn save_data(&self, ram: &Ram, _now: u64) -> Vec<u8> {
+            let mut v = ram.data().to_vec();
+            v.extend_from_slice(&self.cycles.to_le_bytes());
+            v
+        }
+    }
+
+    #[test]
+    fn test_custom_mapper() {
+        let mut v = test_rom(0xfe, 0x01, 0x00);
+        v[0x8000] = 0x42;
+        let mut rom = Rom::new(v);
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::with_mapper(&mut rom, &mut ram, Prototype::default()).unwrap();
+        assert_eq!(cart.header().cartridge_type.mbc, crate::cartridge::header::MbcKind::Custom);
+        cart.write(0x7000, 0x01).unwrap();
+        assert_eq!(cart.read(0x0000).unwrap(), 0x42);
+        assert_eq!(cart.read(0xa000).unwrap(), 0xff);
+        cart.tick(4);
+        assert_eq!(cart.mbc_as::<Prototype>().unwrap().cycles, 4);
+        assert_eq!(cart.save_data(), vec![4, 0, 0, 0]);
+        assert!(cart.mbc_as::<RomOnly>().is_none());
+
+        // prototypes often have a broken header checksum
+        let mut v = test_rom(0xfe, 0x01, 0x00);
+        v[crate::cartridge::header::HEADER_CHECKSUM_ADDR] ^= 0xff;
+        let mut rom = Rom::new(v);
+        let mut ram = Ram::new(vec![]);
+        assert!(Cartridge::new(&mut rom, &mut ram).is_err());
+        assert!(Cartridge::with_mapper(&mut rom, &mut ram, Prototype::default()).is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::mem::rom::Rom;
+use crate::mem::*;
+use super::*;
+
+const LOGO_SIZE: usize = 0x30;
+const MULTICART_ROM_SIZE: usize = 0x100000;
+
+#[derive(Debug)]
+pub struct Mbc1 {
+    ram_enable: bool,
+    bank1: u8, // 5bit rom bank
+    bank2: u8, // 2bit upper rom bank or ram bank
+    mode: bool, // advanced banking mode, bank2 also applies to 0x0000-0x3fff and ram
+    multicart: bool, // MBC1M wires only 4 bits of bank1
+}
+
+impl Mbc1 {
+    pub fn new(multicart: bool) -> Mbc1 {
+        Mbc1 {
+            ram_enable: false,
+            bank1: 1u8,
+            bank2: 0u8,
+            mode: false,
+            multicart,
+        }
+    }
+
+    // MBC1M collections are 1MB and every 256KB game has its own header,
+    // so the logo also appears at the top of bank 0x10
+    pub fn is_multicart(rom: &Rom) -> bool {
+        if rom.len() != MULTICART_ROM_SIZE {
+            return false;
+        }
+        let game = 0x10 * ROM_BANK_SIZE;
+        (LOGO_ADDR..LOGO_ADDR + LOGO_SIZE).all(|addr| rom.get(addr) == rom.get(game + addr))
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        let bank = match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => {
+                if self.mode { self.bank2 << self.bank2_shift() } else { 0 }
+            },
+            _ => {
+                let bank1 = if self.multicart { self.bank1 & 0x0f } else { self.bank1 };
+                (self.bank2 << self.bank2_shift()) | bank1
+            },
+        };
+        bank as usize * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enable {
+            return None;
+        }
+        let bank = if self.mode { self.bank2 as usize } else { 0 };
+        Some(bank * RAM_BANK_SIZE + addr as usize - EXTERNAL_RAM_ADDR_TOP)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            0x2000..=0x3fff => {
+                // 0 is seen as 1 on the full 5 bits, so 0x20/0x40/0x60 can not be mapped to 0x4000
+                self.bank1 = val & 0x1f;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            },
+            0x4000..=0x5fff => self.bank2 = val & 0x03,
+            _ => self.mode = val & 0x01 == 0x01,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mbc1_rom_bank() {
+        let mut mbc = Mbc1::new(false);
+        assert_eq!(mbc.rom_offset(0x4000), 0x4000);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), 0x4000);
+        mbc.write_register(0x2000, 0x20);
+        mbc.write_register(0x4000, 0x01);
+        // bank 0x20 is not reachable, 0x21 is mapped instead
+        assert_eq!(mbc.rom_offset(0x4000), 0x21 * ROM_BANK_SIZE);
+        assert_eq!(mbc.rom_offset(0x0000), 0x0000);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0123), 0x20 * ROM_BANK_SIZE + 0x0123);
+    }
+
+    #[test]
+    fn test_mbc1_ram_bank() {
+        let mut mbc = Mbc1::new(false);
+        assert_eq!(mbc.ram_offset(0xa000), None);
+        mbc.write_register(0x0000, 0x0a);
+        mbc.write_register(0x4000, 0x02);
+        assert_eq!(mbc.ram_offset(0xa001), Some(0x0001));
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.ram_offset(0xa001), Some(2 * RAM_BANK_SIZE + 0x0001));
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.ram_offset(0xa001), None);
+    }
+
+    #[test]
+    fn test_mbc1_multicart() {
+        let mut v = vec![0u8; MULTICART_ROM_SIZE];
+        for i in 0..LOGO_SIZE {
+            v[LOGO_ADDR + i] = i as u8 + 1;
+            v[0x10 * ROM_BANK_SIZE + LOGO_ADDR + i] = i as u8 + 1;
+        }
+        assert!(Mbc1::is_multicart(&Rom::new(v.clone())));
+        v[0x10 * ROM_BANK_SIZE + LOGO_ADDR] = 0;
+        assert!(!Mbc1::is_multicart(&Rom::new(v)));
+
+        let mut mbc = Mbc1::new(true);
+        mbc.write_register(0x4000, 0x01);
+        mbc.write_register(0x2000, 0x13);
+        assert_eq!(mbc.rom_offset(0x4000), 0x13 * ROM_BANK_SIZE);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0000), 0x10 * ROM_BANK_SIZE);
+    }
+}
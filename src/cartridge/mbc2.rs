@@ -0,0 +1,108 @@
+use crate::mem::*;
+use super::*;
+
+// 512 x 4bit ram built in the mbc
+pub const RAM_SIZE: usize = 0x200;
+
+#[derive(Debug)]
+pub struct Mbc2 {
+    ram_enable: bool,
+    rom_bank: u8, // 4bit
+}
+
+impl Mbc2 {
+    pub fn new() -> Mbc2 {
+        Mbc2 {
+            ram_enable: false,
+            rom_bank: 1u8,
+        }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => addr as usize,
+            _ => self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - ROM_BANK_ADDR_TOP),
+        }
+    }
+
+    // the 512 bytes are echoed across 0xa000-0xbfff
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enable {
+            return None;
+        }
+        Some(addr as usize & (RAM_SIZE - 1))
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        // only 0x0000-0x3fff is connected, bit 8 of the address selects the register
+        if addr as usize > ROM_ADDR_TAIL {
+            return;
+        }
+        if addr & 0x0100 == 0 {
+            self.ram_enable = val & 0x0f == 0x0a;
+        } else {
+            self.rom_bank = val & 0x0f;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    fn ram_size(&self) -> Option<usize> {
+        Some(RAM_SIZE)
+    }
+
+    // upper nibble of mbc2 ram is not connected
+    fn read_ram(&self, ram: &Ram, addr: u16) -> u8 {
+        read_mapped_ram(self, ram, addr) | 0xf0
+    }
+
+    fn write_ram(&mut self, ram: &mut Ram, addr: u16, val: u8) {
+        write_mapped_ram(self, ram, addr, val & 0x0f);
+    }
+}
+
+impl Default for Mbc2 {
+    fn default() -> Self {
+        Mbc2::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::mem::ram::Ram;
+    use crate::mem::rom::Rom;
+
+    #[test]
+    fn test_mbc2_register() {
+        let mut mbc = Mbc2::new();
+        // bit 8 set, rom bank
+        mbc.write_register(0x2100, 0x0a);
+        assert_eq!(mbc.ram_offset(0xa000), None);
+        assert_eq!(mbc.rom_offset(0x4000), 0x0a * ROM_BANK_SIZE);
+        mbc.write_register(0x0100, 0x00);
+        assert_eq!(mbc.rom_offset(0x7fff), ROM_BANK_SIZE + 0x3fff);
+        // bit 8 clear, ram enable
+        mbc.write_register(0x0000, 0x0a);
+        assert_eq!(mbc.ram_offset(0xa201), Some(0x0001));
+        mbc.write_register(0x4100, 0x03);
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_SIZE);
+    }
+
+    #[test]
+    fn test_mbc2_ram() {
+        let mut rom = Rom::new(test_rom(0x06, 0x03, 0x00));
+        let mut ram = Ram::new(Vec::new());
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        assert_eq!(cart.ram().len(), RAM_SIZE);
+        cart.write(0x0000, 0x0a).unwrap();
+        cart.write(0xa010, 0x5c).unwrap();
+        assert_eq!(cart.read(0xa010).unwrap(), 0xfc);
+        assert_eq!(cart.read(0xbe10).unwrap(), 0xfc);
+        assert_eq!(cart.ram().get(0x10), Some(0x0c));
+    }
+}
@@ -0,0 +1,417 @@
+use crate::mem::*;
+use super::*;
+
+pub const CPU_CLOCK_HZ: u32 = 4_194_304;
+
+pub const RTC_S: u8 = 0x08;
+pub const RTC_M: u8 = 0x09;
+pub const RTC_H: u8 = 0x0a;
+pub const RTC_DL: u8 = 0x0b;
+pub const RTC_DH: u8 = 0x0c;
+
+const DH_DAY_HIGH: u8 = 0b0000_0001;
+const DH_HALT: u8 = 0b0100_0000;
+const DH_CARRY: u8 = 0b1000_0000;
+
+// the footer VBA-M, BGB and mGBA append to the save, 5 current and 5 latched registers
+// as 32bit words and the unix time of the save as 64bit, or 32bit in the older 44 bytes one
+pub const RTC_FOOTER_SIZE: usize = 48;
+pub const RTC_FOOTER_SIZE_OLD: usize = 44;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rtc {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16, // 9bit day counter
+    pub halt: bool,
+    pub carry: bool, // day counter overflow
+    latched: [u8; 5],
+    cycles: u64, // sub second counter
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc::default()
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        if self.halt {
+            return;
+        }
+        self.cycles += cycles;
+        if self.cycles >= CPU_CLOCK_HZ as u64 {
+            self.advance_seconds(self.cycles / CPU_CLOCK_HZ as u64);
+            self.cycles %= CPU_CLOCK_HZ as u64;
+        }
+    }
+
+    pub fn advance_seconds(&mut self, seconds: u64) {
+        if self.halt {
+            return;
+        }
+        // step through invalid values one by one, the rest can be counted at once
+        let mut seconds = seconds;
+        while seconds > 0 && (self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24) {
+            self.advance_second();
+            seconds -= 1;
+        }
+        let total = self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600 + self.days as u64 * 86400 + seconds;
+        let days = total / 86400;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        self.days = (days & 0x1ff) as u16;
+        if days > 0x1ff {
+            self.carry = true;
+        }
+    }
+
+    // registers holding an invalid value count up to the limit of their bit width
+    // and wrap to 0 without carrying
+    fn advance_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3f;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3f;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1f;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1ff {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    pub fn register(&self, reg: u8) -> u8 {
+        match reg {
+            RTC_S => self.seconds,
+            RTC_M => self.minutes,
+            RTC_H => self.hours,
+            RTC_DL => self.days as u8,
+            RTC_DH => {
+                let mut dh = (self.days >> 8) as u8 & DH_DAY_HIGH;
+                if self.halt {
+                    dh |= DH_HALT;
+                }
+                if self.carry {
+                    dh |= DH_CARRY;
+                }
+                dh
+            },
+            _ => 0xff,
+        }
+    }
+
+    pub fn latch(&mut self) {
+        for (i, reg) in (RTC_S..=RTC_DH).enumerate() {
+            self.latched[i] = self.register(reg);
+        }
+    }
+
+    pub fn latched(&self, reg: u8) -> u8 {
+        match reg {
+            RTC_S..=RTC_DH => self.latched[(reg - RTC_S) as usize],
+            _ => 0xff,
+        }
+    }
+
+    pub fn set_latched(&mut self, reg: u8, val: u8) {
+        if let RTC_S..=RTC_DH = reg {
+            self.latched[(reg - RTC_S) as usize] = val;
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            RTC_S => {
+                self.seconds = val & 0x3f;
+                self.cycles = 0;
+            },
+            RTC_M => self.minutes = val & 0x3f,
+            RTC_H => self.hours = val & 0x1f,
+            RTC_DL => self.days = (self.days & 0x100) | val as u16,
+            RTC_DH => {
+                self.days = (self.days & 0xff) | (((val & DH_DAY_HIGH) as u16) << 8);
+                self.halt = val & DH_HALT != 0;
+                self.carry = val & DH_CARRY != 0;
+            },
+            _ => return,
+        }
+        // the written value is visible in the latched registers right away
+        self.set_latched(reg, self.register(reg));
+    }
+
+    pub fn footer(&self, now: u64) -> Vec<u8> {
+        let mut v = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for reg in RTC_S..=RTC_DH {
+            v.extend_from_slice(&(self.register(reg) as u32).to_le_bytes());
+        }
+        for reg in RTC_S..=RTC_DH {
+            v.extend_from_slice(&(self.latched(reg) as u32).to_le_bytes());
+        }
+        v.extend_from_slice(&now.to_le_bytes());
+        v
+    }
+
+    // the 48 or 44 byte footer sets both register sets, then the rtc runs the seconds since the
+    // stamp unless it was halted. false for any other size
+    pub fn load_footer(&mut self, data: &[u8], now: u64) -> bool {
+        let word = |i: usize| u32::from_le_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]]);
+        let saved = match data.len() {
+            RTC_FOOTER_SIZE => u64::from_le_bytes([data[40], data[41], data[42], data[43], data[44], data[45], data[46], data[47]]),
+            RTC_FOOTER_SIZE_OLD => word(10) as u64,
+            _ => return false,
+        };
+        for (i, reg) in (RTC_S..=RTC_DH).enumerate() {
+            self.write(reg, word(i) as u8);
+        }
+        for (i, reg) in (RTC_S..=RTC_DH).enumerate() {
+            self.set_latched(reg, word(i + 5) as u8);
+        }
+        self.advance_seconds(now.saturating_sub(saved));
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Mbc3 {
+    ram_enable: bool, // also enables the rtc registers
+    rom_bank: u8,
+    select: u8, // ram bank or rtc register
+    latch_prev: u8,
+    mbc30: bool, // 8bit rom bank and 8 ram banks
+    rtc: Option<Rtc>,
+    rtc_changed: bool,
+}
+
+impl Mbc3 {
+    pub fn new(mbc30: bool, timer: bool) -> Mbc3 {
+        Mbc3 {
+            ram_enable: false,
+            rom_bank: 1u8,
+            select: 0u8,
+            latch_prev: 0xffu8,
+            mbc30,
+            rtc: if timer { Some(Rtc::new()) } else { None },
+            rtc_changed: false,
+        }
+    }
+
+    // MBC30 is only found by its larger rom or ram
+    pub fn is_mbc30(header: &CartridgeHeader) -> bool {
+        header.rom_size > 0x200000 || header.ram_size > 0x8000
+    }
+
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    pub fn rtc_footer(&self, now: u64) -> Vec<u8> {
+        self.rtc.as_ref().map(|rtc| rtc.footer(now)).unwrap_or_default()
+    }
+
+    pub fn load_rtc_footer(&mut self, data: &[u8], now: u64) -> bool {
+        self.rtc.as_mut().is_some_and(|rtc| rtc.load_footer(data, now))
+    }
+
+    // Some when a rtc register is mapped to 0xa000-0xbfff
+    pub fn read_rtc(&self) -> Option<u8> {
+        match &self.rtc {
+            Some(rtc) if self.ram_enable && (RTC_S..=RTC_DH).contains(&self.select) => Some(rtc.latched(self.select)),
+            _ => None,
+        }
+    }
+
+    pub fn write_rtc(&mut self, val: u8) -> bool {
+        let select = self.select;
+        match &mut self.rtc {
+            Some(rtc) if self.ram_enable && (RTC_S..=RTC_DH).contains(&select) => {
+                rtc.write(select, val);
+                self.rtc_changed = true;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => addr as usize,
+            _ => self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - ROM_BANK_ADDR_TOP),
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        let banks = if self.mbc30 { 0x08 } else { 0x04 };
+        if !self.ram_enable || self.select >= banks {
+            return None;
+        }
+        Some(self.select as usize * RAM_BANK_SIZE + addr as usize - EXTERNAL_RAM_ADDR_TOP)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            0x2000..=0x3fff => {
+                self.rom_bank = if self.mbc30 { val } else { val & 0x7f };
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            },
+            0x4000..=0x5fff => self.select = val & 0x0f,
+            _ => {
+                // writing 0x00 then 0x01 latches the clock
+                if self.latch_prev == 0x00 && val == 0x01 {
+                    if let Some(rtc) = &mut self.rtc {
+                        rtc.latch();
+                        self.rtc_changed = true;
+                    }
+                }
+                self.latch_prev = val;
+            },
+        }
+    }
+
+    fn tick_rtc(&mut self, cycles: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    fn take_clock_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.rtc_changed, false)
+    }
+
+    fn read_ram(&self, ram: &Ram, addr: u16) -> u8 {
+        self.read_rtc().unwrap_or_else(|| read_mapped_ram(self, ram, addr))
+    }
+
+    fn write_ram(&mut self, ram: &mut Ram, addr: u16, val: u8) {
+        if !self.write_rtc(val) {
+            write_mapped_ram(self, ram, addr, val);
+        }
+    }
+
+    // the rtc footer follows the ram
+    fn save_data(&self, ram: &Ram, now: u64) -> Vec<u8> {
+        let mut v = ram.data().to_vec();
+        v.extend(self.rtc_footer(now));
+        v
+    }
+
+    fn load_save_data(&mut self, ram: &mut Ram, data: &[u8], now: u64) {
+        let footer = load_ram(ram, data);
+        if !footer.is_empty() {
+            self.load_rtc_footer(footer, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtc_tick() {
+        let mut rtc = Rtc::new();
+        rtc.write(RTC_S, 59);
+        rtc.write(RTC_M, 59);
+        rtc.write(RTC_H, 23);
+        rtc.write(RTC_DL, 0xff);
+        rtc.write(RTC_DH, 0x01);
+        rtc.tick(CPU_CLOCK_HZ as u64 - 1);
+        assert_eq!(rtc.seconds, 59);
+        rtc.tick(1);
+        assert_eq!((rtc.seconds, rtc.minutes, rtc.hours, rtc.days), (0, 0, 0, 0));
+        assert!(rtc.carry);
+
+        // invalid values wrap at their bit width
+        rtc.write(RTC_S, 63);
+        rtc.advance_seconds(1);
+        assert_eq!((rtc.seconds, rtc.minutes), (0, 0));
+
+        rtc.write(RTC_DH, DH_HALT);
+        rtc.advance_seconds(10);
+        assert_eq!(rtc.seconds, 0);
+    }
+
+    #[test]
+    fn test_rtc_footer() {
+        let mut rtc = Rtc::new();
+        rtc.write(RTC_H, 23);
+        rtc.write(RTC_DL, 0xff);
+        rtc.latch();
+        rtc.write(RTC_M, 59);
+        let footer = rtc.footer(1_000_000);
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+        assert_eq!(&footer[4..8], &[59, 0, 0, 0]);
+        assert_eq!(&footer[28..32], &[23, 0, 0, 0]);
+
+        // an hour and a minute later
+        let mut loaded = Rtc::new();
+        assert!(loaded.load_footer(&footer, 1_000_000 + 3660));
+        assert_eq!((loaded.minutes, loaded.hours, loaded.days), (0, 1, 0x100));
+        assert_eq!(loaded.latched(RTC_H), 23);
+
+        let mut old = footer[..RTC_FOOTER_SIZE_OLD].to_vec();
+        old[40..44].copy_from_slice(&1_000_000u32.to_le_bytes());
+        assert!(loaded.load_footer(&old, 1_000_001));
+        assert_eq!((loaded.seconds, loaded.minutes, loaded.hours), (1, 59, 23));
+        assert!(!loaded.load_footer(&footer[..40], 0));
+
+        // more than 512 days set the carry
+        loaded.advance_seconds(86400 * 512);
+        assert!(loaded.carry);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_latch() {
+        let mut mbc = Mbc3::new(false, true);
+        mbc.write_register(0x0000, 0x0a);
+        mbc.rtc_mut().unwrap().advance_seconds(61);
+        mbc.write_register(0x4000, RTC_S);
+        assert_eq!(mbc.ram_offset(0xa000), None);
+        assert_eq!(mbc.read_rtc(), Some(0));
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_rtc(), Some(1));
+        mbc.write_register(0x4000, RTC_M);
+        assert_eq!(mbc.read_rtc(), Some(1));
+
+        mbc.write_register(0x4000, 0x03);
+        assert_eq!(mbc.read_rtc(), None);
+        assert_eq!(mbc.ram_offset(0xa000), Some(3 * RAM_BANK_SIZE));
+        mbc.write_register(0x4000, 0x07);
+        assert_eq!(mbc.ram_offset(0xa000), None);
+    }
+
+    #[test]
+    fn test_mbc30_bank() {
+        let mut mbc = Mbc3::new(true, false);
+        mbc.write_register(0x0000, 0x0a);
+        mbc.write_register(0x2000, 0xff);
+        assert_eq!(mbc.rom_offset(0x4000), 0xff * ROM_BANK_SIZE);
+        mbc.write_register(0x4000, 0x07);
+        assert_eq!(mbc.ram_offset(0xa000), Some(7 * RAM_BANK_SIZE));
+
+        let mut mbc = Mbc3::new(false, false);
+        mbc.write_register(0x2000, 0x80);
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_SIZE);
+    }
+}
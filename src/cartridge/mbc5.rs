@@ -0,0 +1,112 @@
+use crate::mem::*;
+use super::*;
+
+const RUMBLE_MOTOR: u8 = 0b0000_1000;
+
+#[derive(Debug)]
+pub struct Mbc5 {
+    ram_enable: bool,
+    rom_bank: u16, // 9bit
+    ram_bank: u8,
+    rumble: bool, // cartridge with a motor wired to bit 3 of the ram bank register
+    motor: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rumble: bool) -> Mbc5 {
+        Mbc5 {
+            ram_enable: false,
+            rom_bank: 1u16,
+            ram_bank: 0u8,
+            rumble,
+            motor: false,
+        }
+    }
+
+    pub fn motor(&self) -> bool {
+        self.motor
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => addr as usize,
+            // bank 0 can be mapped to 0x4000-0x7fff unlike older mbcs
+            _ => self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - ROM_BANK_ADDR_TOP),
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enable {
+            return None;
+        }
+        Some(self.ram_bank as usize * RAM_BANK_SIZE + addr as usize - EXTERNAL_RAM_ADDR_TOP)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | val as u16,
+            0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0xff) | (((val & 0x01) as u16) << 8),
+            0x4000..=0x5fff => {
+                if self.rumble {
+                    self.motor = val & RUMBLE_MOTOR != 0;
+                    self.ram_bank = val & 0x07;
+                } else {
+                    self.ram_bank = val & 0x0f;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn rumble(&self) -> bool {
+        self.motor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::mem::ram::Ram;
+    use crate::mem::rom::Rom;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_mbc5_bank() {
+        let mut mbc = Mbc5::new(false);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), 0x0000);
+        mbc.write_register(0x2000, 0x23);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mbc.rom_offset(0x4001), 0x123 * ROM_BANK_SIZE + 1);
+        mbc.write_register(0x0000, 0x0a);
+        mbc.write_register(0x4000, 0x0f);
+        assert_eq!(mbc.ram_offset(0xa000), Some(0x0f * RAM_BANK_SIZE));
+        assert!(!mbc.motor());
+    }
+
+    #[test]
+    fn test_mbc5_rumble() {
+        let mut rom = Rom::new(test_rom(0x1e, 0x01, 0x03));
+        let mut ram = Ram::new(Vec::new());
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let e = events.clone();
+        cart.set_rumble_handler(move |on| e.borrow_mut().push(on));
+
+        cart.write(0x4000, 0x0b).unwrap();
+        cart.write(0x4000, 0x0a).unwrap();
+        cart.write(0x4000, 0x02).unwrap();
+        assert_eq!(*events.borrow(), vec![true, false]);
+        assert!(!cart.rumble());
+        let mbc = cart.mbc_as::<Mbc5>().expect("not mbc5");
+        assert_eq!(mbc.ram_offset(0xa000), None);
+        cart.write(0x0000, 0x0a).unwrap();
+        let mbc = cart.mbc_as::<Mbc5>().expect("not mbc5");
+        assert_eq!(mbc.ram_offset(0xa000), Some(2 * RAM_BANK_SIZE));
+    }
+}
@@ -0,0 +1,373 @@
+use crate::mem::ram::Ram;
+use crate::mem::*;
+use super::*;
+
+// 93LC56 in 16bit mode, 128 words
+pub const EEPROM_SIZE: usize = 0x100;
+const EEPROM_WORDS: usize = EEPROM_SIZE / 2;
+
+const ACCEL_CENTER: u16 = 0x81d0;
+const ACCEL_ERASED: u16 = 0x8000;
+const ACCEL_1G: f32 = 112.0; // 0x70
+
+// eeprom pins on the register at 0xax8x
+const PIN_CS: u8 = 0b1000_0000;
+const PIN_CLK: u8 = 0b0100_0000;
+const PIN_DI: u8 = 0b0000_0010;
+const PIN_DO: u8 = 0b0000_0001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EepromState {
+    Idle,
+    Command { bits: u8, shift: u16 },
+    Read { addr: usize, bits: u8, shift: u16 },
+    Write { addr: usize, all: bool, bits: u8, shift: u16 },
+}
+
+// words are stored big endian in the cartridge ram
+#[derive(Debug)]
+pub struct Eeprom {
+    cs: bool,
+    clk: bool,
+    di: bool,
+    out: bool,
+    write_enable: bool,
+    state: EepromState,
+}
+
+impl Eeprom {
+    pub fn new() -> Eeprom {
+        Eeprom {
+            cs: false,
+            clk: false,
+            di: false,
+            out: true,
+            write_enable: false,
+            state: EepromState::Idle,
+        }
+    }
+
+    pub fn pins(&self) -> u8 {
+        let mut val = 0u8;
+        if self.cs {
+            val |= PIN_CS;
+        }
+        if self.clk {
+            val |= PIN_CLK;
+        }
+        if self.di {
+            val |= PIN_DI;
+        }
+        if self.out {
+            val |= PIN_DO;
+        }
+        val
+    }
+
+    pub fn write(&mut self, storage: &mut Ram, val: u8) {
+        let cs = val & PIN_CS != 0;
+        let clk = val & PIN_CLK != 0;
+        let di = val & PIN_DI != 0;
+        if !cs {
+            // deselecting aborts any command
+            self.state = EepromState::Idle;
+            self.out = true;
+        } else if !self.clk && clk {
+            self.clock(storage, di);
+        }
+        self.cs = cs;
+        self.clk = clk;
+        self.di = di;
+    }
+
+    // a bit is shifted in on every rising edge of clk
+    fn clock(&mut self, storage: &mut Ram, di: bool) {
+        let bit = di as u16;
+        self.state = match self.state {
+            // waiting for the start bit
+            EepromState::Idle => {
+                if di { EepromState::Command { bits: 0, shift: 0 } } else { EepromState::Idle }
+            },
+            EepromState::Command { bits, shift } => {
+                let shift = (shift << 1) | bit;
+                if bits + 1 < 10 {
+                    EepromState::Command { bits: bits + 1, shift }
+                } else {
+                    self.command(storage, shift)
+                }
+            },
+            EepromState::Read { addr, bits, shift } => {
+                self.out = shift & 0x8000 != 0;
+                if bits + 1 < 16 {
+                    EepromState::Read { addr, bits: bits + 1, shift: shift << 1 }
+                } else {
+                    // sequential read goes on with the next word
+                    let addr = (addr + 1) % EEPROM_WORDS;
+                    EepromState::Read { addr, bits: 0, shift: Eeprom::word(storage, addr) }
+                }
+            },
+            EepromState::Write { addr, all, bits, shift } => {
+                let shift = (shift << 1) | bit;
+                if bits + 1 < 16 {
+                    EepromState::Write { addr, all, bits: bits + 1, shift }
+                } else {
+                    if self.write_enable {
+                        if all {
+                            (0..EEPROM_WORDS).for_each(|addr| Eeprom::set_word(storage, addr, shift));
+                        } else {
+                            Eeprom::set_word(storage, addr, shift);
+                        }
+                    }
+                    self.out = true;
+                    EepromState::Idle
+                }
+            },
+        };
+    }
+
+    // 2bit opcode and 8bit address
+    fn command(&mut self, storage: &mut Ram, shift: u16) -> EepromState {
+        let addr = (shift & 0x7f) as usize;
+        match (shift >> 8) & 0b11 {
+            0b10 => {
+                // a dummy 0 comes before the data
+                self.out = false;
+                EepromState::Read { addr, bits: 0, shift: Eeprom::word(storage, addr) }
+            },
+            0b01 => EepromState::Write { addr, all: false, bits: 0, shift: 0 },
+            0b11 => {
+                if self.write_enable {
+                    Eeprom::set_word(storage, addr, 0xffff);
+                }
+                self.out = true;
+                EepromState::Idle
+            },
+            _ => match (shift >> 6) & 0b11 {
+                0b00 => {
+                    self.write_enable = false;
+                    EepromState::Idle
+                },
+                0b11 => {
+                    self.write_enable = true;
+                    EepromState::Idle
+                },
+                0b10 => {
+                    if self.write_enable {
+                        (0..EEPROM_WORDS).for_each(|addr| Eeprom::set_word(storage, addr, 0xffff));
+                    }
+                    self.out = true;
+                    EepromState::Idle
+                },
+                _ => EepromState::Write { addr: 0, all: true, bits: 0, shift: 0 },
+            },
+        }
+    }
+
+    fn word(storage: &Ram, addr: usize) -> u16 {
+        u16::from_be_bytes([storage.get(addr * 2).unwrap_or(0xff), storage.get(addr * 2 + 1).unwrap_or(0xff)])
+    }
+
+    fn set_word(storage: &mut Ram, addr: usize, val: u16) {
+        let [hi, lo] = val.to_be_bytes();
+        storage.set(addr * 2, hi);
+        storage.set(addr * 2 + 1, lo);
+    }
+}
+
+impl Default for Eeprom {
+    fn default() -> Self {
+        Eeprom::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct Mbc7 {
+    ram_enable1: bool,
+    ram_enable2: bool,
+    rom_bank: u8,
+    tilt: (f32, f32), // host input in g
+    accel_x: u16,
+    accel_y: u16,
+    latch_ready: bool,
+    eeprom: Eeprom,
+}
+
+impl Mbc7 {
+    pub fn new() -> Mbc7 {
+        Mbc7 {
+            ram_enable1: false,
+            ram_enable2: false,
+            rom_bank: 1u8,
+            tilt: (0.0, 0.0),
+            accel_x: ACCEL_ERASED,
+            accel_y: ACCEL_ERASED,
+            latch_ready: false,
+            eeprom: Eeprom::new(),
+        }
+    }
+
+    // x and y are in g, positive to the right and to the bottom, clamped to +-2g
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x.clamp(-2.0, 2.0), y.clamp(-2.0, 2.0));
+    }
+
+    fn register(addr: u16) -> Option<u8> {
+        match addr {
+            0xa000..=0xafff => Some(((addr >> 4) & 0x0f) as u8),
+            _ => None,
+        }
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enable1 || !self.ram_enable2 {
+            return 0xff;
+        }
+        match Mbc7::register(addr) {
+            Some(0x2) => self.accel_x as u8,
+            Some(0x3) => (self.accel_x >> 8) as u8,
+            Some(0x4) => self.accel_y as u8,
+            Some(0x5) => (self.accel_y >> 8) as u8,
+            Some(0x6) => 0x00,
+            Some(0x8) => self.eeprom.pins(),
+            _ => 0xff,
+        }
+    }
+}
+
+impl Mapper for Mbc7 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => addr as usize,
+            _ => self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - ROM_BANK_ADDR_TOP),
+        }
+    }
+
+    // the eeprom is not mapped to memory
+    fn ram_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enable1 = val & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = val & 0x7f,
+            0x4000..=0x5fff => self.ram_enable2 = val == 0x40,
+            _ => {},
+        }
+    }
+
+    fn write_ram(&mut self, storage: &mut Ram, addr: u16, val: u8) {
+        if !self.ram_enable1 || !self.ram_enable2 {
+            return;
+        }
+        match Mbc7::register(addr) {
+            // 0x55 then 0xaa latches the accelerometer
+            Some(0x0) if val == 0x55 => {
+                self.accel_x = ACCEL_ERASED;
+                self.accel_y = ACCEL_ERASED;
+                self.latch_ready = true;
+            },
+            Some(0x1) if val == 0xaa && self.latch_ready => {
+                self.accel_x = (ACCEL_CENTER as f32 + self.tilt.0 * ACCEL_1G) as u16;
+                self.accel_y = (ACCEL_CENTER as f32 + self.tilt.1 * ACCEL_1G) as u16;
+                self.latch_ready = false;
+            },
+            Some(0x8) => self.eeprom.write(storage, val),
+            _ => {},
+        }
+    }
+
+    fn ram_size(&self) -> Option<usize> {
+        Some(EEPROM_SIZE)
+    }
+
+    // the eeprom is behind the registers, the storage is not mapped
+    fn read_ram(&self, _ram: &Ram, addr: u16) -> u8 {
+        Mbc7::read_ram(self, addr)
+    }
+}
+
+impl Default for Mbc7 {
+    fn default() -> Self {
+        Mbc7::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(mbc: &mut Mbc7, storage: &mut Ram, bits: &[u8]) {
+        for bit in bits {
+            let di = if *bit == 1 { PIN_DI } else { 0 };
+            mbc.write_ram(storage, 0xa080, PIN_CS | di);
+            mbc.write_ram(storage, 0xa080, PIN_CS | PIN_CLK | di);
+        }
+    }
+
+    fn bits(val: u16, len: usize) -> Vec<u8> {
+        (0..len).rev().map(|i| ((val >> i) & 1) as u8).collect()
+    }
+
+    fn enable(mbc: &mut Mbc7) {
+        mbc.write_register(0x0000, 0x0a);
+        mbc.write_register(0x4000, 0x40);
+    }
+
+    #[test]
+    fn test_mbc7_accelerometer() {
+        let mut mbc = Mbc7::new();
+        let mut storage = Ram::new(vec![0xff; EEPROM_SIZE]);
+        assert_eq!(mbc.read_ram(0xa020), 0xff);
+        enable(&mut mbc);
+        mbc.set_tilt(1.0, -0.5);
+        mbc.write_ram(&mut storage, 0xa000, 0x55);
+        assert_eq!(mbc.read_ram(0xa030), 0x80);
+        mbc.write_ram(&mut storage, 0xa010, 0xaa);
+        let x = ACCEL_CENTER + 0x70;
+        let y = ACCEL_CENTER - 0x38;
+        assert_eq!(mbc.read_ram(0xa020), x as u8);
+        assert_eq!(mbc.read_ram(0xa030), (x >> 8) as u8);
+        assert_eq!(mbc.read_ram(0xa040), y as u8);
+        assert_eq!(mbc.read_ram(0xa050), (y >> 8) as u8);
+
+        // a second 0xaa without 0x55 does not latch again
+        mbc.set_tilt(0.0, 0.0);
+        mbc.write_ram(&mut storage, 0xa010, 0xaa);
+        assert_eq!(mbc.read_ram(0xa020), x as u8);
+    }
+
+    #[test]
+    fn test_mbc7_eeprom() {
+        let mut mbc = Mbc7::new();
+        let mut storage = Ram::new(vec![0xff; EEPROM_SIZE]);
+        enable(&mut mbc);
+
+        // write is ignored until EWEN
+        let mut write = vec![1];
+        write.extend(bits(0b01_0000_0011, 10));
+        write.extend(bits(0xbeef, 16));
+        send(&mut mbc, &mut storage, &write);
+        mbc.write_ram(&mut storage, 0xa080, 0x00);
+        assert_eq!(storage.get(6), Some(0xff));
+
+        let mut ewen = vec![1];
+        ewen.extend(bits(0b00_1100_0000, 10));
+        send(&mut mbc, &mut storage, &ewen);
+        mbc.write_ram(&mut storage, 0xa080, 0x00);
+        send(&mut mbc, &mut storage, &write);
+        mbc.write_ram(&mut storage, 0xa080, 0x00);
+        assert_eq!((storage.get(6), storage.get(7)), (Some(0xbe), Some(0xef)));
+
+        let mut read = vec![1];
+        read.extend(bits(0b10_0000_0011, 10));
+        send(&mut mbc, &mut storage, &read);
+        assert_eq!(mbc.read_ram(0xa080) & PIN_DO, 0);
+        let mut word = 0u16;
+        for _ in 0..16 {
+            send(&mut mbc, &mut storage, &[0]);
+            word = (word << 1) | (mbc.read_ram(0xa080) & PIN_DO) as u16;
+        }
+        assert_eq!(word, 0xbeef);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::mem::rom::Rom;
+use crate::mem::*;
+use super::*;
+
+// the menu and the header of the collection are in the last 32KB of the rom
+const MENU_SIZE: usize = 0x8000;
+
+#[derive(Debug)]
+pub struct Mmm01 {
+    locked: bool, // a game is mapped and the base bank can not be changed anymore
+    base: usize, // first 16KB bank of the selected game
+    ram_enable: bool,
+    rom_bank: usize,
+    ram_bank: u8,
+}
+
+impl Mmm01 {
+    pub fn new(rom_size: usize) -> Mmm01 {
+        let menu = rom_size.saturating_sub(MENU_SIZE) / ROM_BANK_SIZE;
+        Mmm01 {
+            locked: false,
+            base: menu,
+            ram_enable: false,
+            rom_bank: menu + 1,
+            ram_bank: 0u8,
+        }
+    }
+
+    // the header at the top of the rom belongs to the first game, the real one is in the menu
+    pub fn is_mmm01(rom: &Rom) -> bool {
+        if rom.len() < MENU_SIZE * 2 {
+            return false;
+        }
+        let code = rom.get(Mmm01::header_addr(rom.len(), CARTRIDGE_TYPE_ADDR)).unwrap_or(0);
+        (0x0b..=0x0d).contains(&code)
+    }
+
+    pub fn header_addr(rom_size: usize, addr: usize) -> usize {
+        rom_size - MENU_SIZE + addr
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl Mapper for Mmm01 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => self.base * ROM_BANK_SIZE + addr as usize,
+            _ => self.rom_bank * ROM_BANK_SIZE + (addr as usize - ROM_BANK_ADDR_TOP),
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enable {
+            return None;
+        }
+        Some(self.ram_bank as usize * RAM_BANK_SIZE + addr as usize - EXTERNAL_RAM_ADDR_TOP)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        if !self.locked {
+            // the menu picks a game in 32KB units, then maps it by writing to 0x0000-0x1fff
+            match addr {
+                0x0000..=0x1fff => {
+                    self.locked = true;
+                    self.rom_bank = self.base + 1;
+                },
+                0x2000..=0x3fff => self.base = val as usize * 2,
+                _ => {},
+            }
+            return;
+        }
+        match addr {
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = self.base + val.max(1) as usize,
+            0x4000..=0x5fff => self.ram_bank = val & 0x03,
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::mem::ram::Ram;
+
+    #[test]
+    fn test_mmm01() {
+        // 4 games of 32KB, the last one is the menu
+        let menu = test_rom(0x0d, 0x00, 0x02);
+        let mut v = test_rom(0x00, 0x02, 0x00);
+        v[0x18000..].copy_from_slice(&menu);
+        for bank in 0..8 {
+            v[bank * ROM_BANK_SIZE + 0x1000] = bank as u8;
+        }
+        let mut rom = Rom::new(v);
+        assert!(Mmm01::is_mmm01(&rom));
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        assert_eq!(cart.header().cartridge_type.mbc, MbcKind::Mmm01);
+        assert_eq!(cart.ram().len(), 0x2000);
+        assert_eq!((cart.read(0x1000).unwrap(), cart.read(0x5000).unwrap()), (6, 7));
+
+        // map the second game
+        cart.write(0x2000, 0x01).unwrap();
+        cart.write(0x0000, 0x00).unwrap();
+        assert_eq!((cart.read(0x1000).unwrap(), cart.read(0x5000).unwrap()), (2, 3));
+        cart.write(0x2000, 0x00).unwrap();
+        assert_eq!(cart.read(0x5000).unwrap(), 3);
+
+        // the game can not leave its banks through the base register anymore
+        cart.write(0x0000, 0x0a).unwrap();
+        cart.write(0x2000, 0x01).unwrap();
+        assert_eq!(cart.read(0x1000).unwrap(), 2);
+        cart.write(0xa000, 0x12).unwrap();
+        assert_eq!(cart.read(0xa000).unwrap(), 0x12);
+    }
+}
@@ -1,21 +1,404 @@
+pub mod header;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod mbc7;
+pub mod huc1;
+pub mod huc3;
+pub mod camera;
+pub mod flash;
+pub mod save;
+pub mod clock;
+pub mod mmm01;
+pub mod unlicensed;
+pub mod mapper;
+
+use std::any::Any;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
 use super::mem::ram::Ram;
 use super::mem::rom::Rom;
+use super::mem::*;
+use crate::device::Device;
+use crate::error::*;
+use header::*;
+use mbc1::Mbc1;
+use mbc2::Mbc2;
+use mbc3::Mbc3;
+use mbc5::Mbc5;
+use mbc7::Mbc7;
+use huc1::HuC1;
+use huc3::HuC3;
+use camera::PocketCamera;
+use flash::{Flash, FlashChip};
+use clock::{Clock, CycleClock};
+use mmm01::Mmm01;
+use unlicensed::{Bbd, Sachen, WisdomTree, M161};
+pub use mapper::*;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+// the mapper of the cartridge type in the header
+pub fn new_mapper(header: &CartridgeHeader, rom: &Rom) -> GBResult<Box<dyn Mapper>> {
+    let mbc: Box<dyn Mapper> = match header.cartridge_type.mbc {
+        MbcKind::None => Box::new(RomOnly),
+        MbcKind::Mbc1 => Box::new(Mbc1::new(Mbc1::is_multicart(rom))),
+        MbcKind::Mbc2 => Box::new(Mbc2::new()),
+        MbcKind::Mbc3 => Box::new(Mbc3::new(Mbc3::is_mbc30(header), header.cartridge_type.timer)),
+        MbcKind::Mbc5 => Box::new(Mbc5::new(header.cartridge_type.rumble)),
+        MbcKind::Mbc7 => Box::new(Mbc7::new()),
+        MbcKind::HuC1 => Box::new(HuC1::new()),
+        MbcKind::HuC3 => Box::new(HuC3::new()),
+        MbcKind::PocketCamera => Box::new(PocketCamera::new()),
+        MbcKind::Mmm01 => Box::new(Mmm01::new(rom.len())),
+        MbcKind::WisdomTree => Box::new(WisdomTree::new()),
+        MbcKind::SachenMmc1 => Box::new(Sachen::unlocked(false)),
+        MbcKind::SachenMmc2 => Box::new(Sachen::unlocked(true)),
+        MbcKind::M161 => Box::new(M161::new()),
+        MbcKind::Bbd => Box::new(Bbd::new(false)),
+        MbcKind::Hitek => Box::new(Bbd::new(true)),
+        _ => return Err(GBError::UnsupportedCartridgeType(header.cartridge_type.code)),
+    };
+    Ok(mbc)
+}
+
+// called with the new motor state when a rumble cartridge turns its motor on or off
+pub type RumbleFn = Box<dyn FnMut(bool)>;
+
+struct RumbleHandler(RumbleFn);
+
+impl fmt::Debug for RumbleHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RumbleHandler")
+    }
+}
+
+// the rom and ram are borrowed from the caller, or owned by a cartridge which has to be 'static
+#[derive(Debug)]
+enum Storage<'a, T> {
+    Borrowed(&'a mut T),
+    Owned(T),
+}
+
+impl<'a, T> Deref for Storage<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Storage::Borrowed(t) => t,
+            Storage::Owned(t) => t,
+        }
+    }
+}
+
+impl<'a, T> DerefMut for Storage<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            Storage::Borrowed(t) => t,
+            Storage::Owned(t) => t,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Cartridge<'a> {
-    rom: &'a Rom,
-    ram: &'a mut Ram,
-    mbc_type: bool,
-    mode: bool,
+    rom: Storage<'a, Rom>,
+    ram: Storage<'a, Ram>,
+    header: CartridgeHeader,
+    mbc: Box<dyn Mapper>,
+    flash: Option<Flash>,
+    ram_dirty: bool,
+    clock: Box<dyn Clock>,
+    rumble_handler: Option<RumbleHandler>,
 }
 
 impl<'a> Cartridge<'a> {
-    pub fn new(rom: &'a Rom, ram: &'a mut Ram, mbc_type: bool, mode: bool) -> Cartridge<'a> {
+    pub fn new(rom: &'a mut Rom, ram: &'a mut Ram) -> GBResult<Cartridge<'a>> {
+        let kind = Cartridge::detect(rom);
+        Cartridge::with_kind(Storage::Borrowed(rom), Storage::Borrowed(ram), kind)
+    }
+
+    // a cartridge which keeps its rom and ram, e.g. for bindings which cannot hold a borrow
+    pub fn owned(rom: Rom, ram: Ram) -> GBResult<Cartridge<'static>> {
+        let kind = Cartridge::detect(&rom);
+        Cartridge::with_kind(Storage::Owned(rom), Storage::Owned(ram), kind)
+    }
+
+    fn detect(rom: &Rom) -> Option<MbcKind> {
+        if Mmm01::is_mmm01(rom) { Some(MbcKind::Mmm01) } else { unlicensed::detect(rom) }
+    }
+
+    // forces the mapper for cartridges the heuristics of new get wrong
+    pub fn with_mbc(rom: &'a mut Rom, ram: &'a mut Ram, kind: MbcKind) -> GBResult<Cartridge<'a>> {
+        Cartridge::with_kind(Storage::Borrowed(rom), Storage::Borrowed(ram), Some(kind))
+    }
+
+    // a mapper which is not built in, e.g. prototype hardware. the header is parsed leniently
+    // as the cartridge type of such a cartridge is often unknown
+    pub fn with_mapper<M>(rom: &'a mut Rom, ram: &'a mut Ram, mapper: M) -> GBResult<Cartridge<'a>>
+    where
+        M: Mapper,
+    {
+        let header = CartridgeHeader::parse_as(rom, |addr| addr, Some(MbcKind::Custom))?;
+        Ok(Cartridge::with_header(Storage::Borrowed(rom), Storage::Borrowed(ram), header, Box::new(mapper)))
+    }
+
+    fn with_kind(rom: Storage<'a, Rom>, ram: Storage<'a, Ram>, kind: Option<MbcKind>) -> GBResult<Cartridge<'a>> {
+        let header = match kind {
+            Some(MbcKind::Mmm01) => {
+                let len = rom.len();
+                CartridgeHeader::parse_as(&rom, |addr| Mmm01::header_addr(len, addr), kind)?
+            },
+            Some(MbcKind::SachenMmc1) | Some(MbcKind::SachenMmc2) => CartridgeHeader::parse_as(&rom, Sachen::header_addr, kind)?,
+            Some(_) => CartridgeHeader::parse_as(&rom, |addr| addr, kind)?,
+            None => CartridgeHeader::parse(&rom)?,
+        };
+        let mbc = new_mapper(&header, &rom)?;
+        Ok(Cartridge::with_header(rom, ram, header, mbc))
+    }
+
+    fn with_header(rom: Storage<'a, Rom>, mut ram: Storage<'a, Ram>, header: CartridgeHeader, mbc: Box<dyn Mapper>) -> Cartridge<'a> {
+        // ram built in the mbc is not counted in the header
+        let ram_size = mbc.ram_size().unwrap_or(header.ram_size);
+        if ram.len() < ram_size {
+            ram.resize(ram_size);
+        }
         Cartridge {
-            rom: rom,
-            ram: ram,
-            mbc_type: mbc_type,
-            mode: mode,
+            rom,
+            ram,
+            header,
+            mbc,
+            flash: None,
+            ram_dirty: false,
+            clock: Box::new(CycleClock::from_host()),
+            rumble_handler: None,
+        }
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    pub fn mbc(&self) -> &dyn Mapper {
+        self.mbc.as_ref()
+    }
+
+    pub fn mbc_mut(&mut self) -> &mut dyn Mapper {
+        self.mbc.as_mut()
+    }
+
+    // the mapper as its concrete type, None when the cartridge has another one
+    pub fn mbc_as<M: Mapper>(&self) -> Option<&M> {
+        (self.mbc.as_ref() as &dyn Any).downcast_ref::<M>()
+    }
+
+    pub fn mbc_as_mut<M: Mapper>(&mut self) -> Option<&mut M> {
+        (self.mbc.as_mut() as &mut dyn Any).downcast_mut::<M>()
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        let rtc_cycles = self.clock.advance(cycles);
+        self.mbc.tick(&mut self.ram, cycles);
+        self.mbc.tick_rtc(rtc_cycles);
+    }
+
+    // the clock runs with the emulated cycles from the time the cartridge was made unless another
+    // one is set, e.g. HostClock for players who want the rtc to follow the real time, or
+    // CycleClock::default() from the epoch for runs which have to be reproduced
+    pub fn set_clock<C>(&mut self, clock: C)
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Box::new(clock);
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.mbc.rumble()
+    }
+
+    pub fn set_rumble_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(bool) + 'static,
+    {
+        self.rumble_handler = Some(RumbleHandler(Box::new(f)));
+    }
+
+    pub fn clear_rumble_handler(&mut self) {
+        self.rumble_handler = None;
+    }
+
+    // the ir led of the cartridge, feed it to the ir input of another instance to link them
+    pub fn ir_led(&self) -> bool {
+        self.mbc.ir_led()
+    }
+
+    pub fn set_ir_input(&mut self, light: bool) {
+        self.mbc.set_ir_input(light);
+    }
+
+    // tone requested from the HuC3 speaker since the last call
+    pub fn take_tone(&mut self) -> Option<u8> {
+        self.mbc_as_mut::<HuC3>().and_then(|mbc| mbc.take_tone())
+    }
+
+    // tilt input of the accelerometer in g, ignored by cartridges without one
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        if let Some(mbc) = self.mbc_as_mut::<Mbc7>() {
+            mbc.set_tilt(x, y);
+        }
+    }
+
+    // image source of the camera sensor, ignored by cartridges without one
+    pub fn set_camera_sensor<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut [u8]) + 'static,
+    {
+        if let Some(mbc) = self.mbc_as_mut::<PocketCamera>() {
+            mbc.set_sensor(f);
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.header.cartridge_type.battery
+    }
+
+    // true once after the contents of the battery backed ram or the clock changed
+    pub fn take_ram_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.ram_dirty, false)
+    }
+
+    fn mark_dirty(&mut self, ram_changed: bool) {
+        let clock_changed = self.mbc.take_clock_changed();
+        if (ram_changed || clock_changed) && self.header.cartridge_type.battery {
+            self.ram_dirty = true;
+        }
+    }
+
+    // contents of a .sav file, the ram followed by what the mapper keeps, e.g. the rtc stamped with the time of the clock
+    pub fn save_data(&self) -> Vec<u8> {
+        self.mbc.save_data(&self.ram, self.clock.now())
+    }
+
+    // saves of a different size are loaded as far as they fit, the rtc catches up with the clock
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.mbc.load_save_data(&mut self.ram, data, self.clock.now());
+        self.ram_dirty = false;
+    }
+
+    // homebrew flash carts replace the rom with a flash chip which the game can program
+    pub fn set_flash(&mut self, chip: Option<FlashChip>) {
+        self.flash = chip.map(Flash::new);
+    }
+
+    pub fn flash(&self) -> Option<&Flash> {
+        self.flash.as_ref()
+    }
+
+    // true once after the flash has been programmed or erased, the rom should be saved then
+    pub fn take_flash_dirty(&mut self) -> bool {
+        self.flash.as_mut().is_some_and(|flash| flash.take_dirty())
+    }
+
+    pub fn rom(&self) -> &Rom {
+        &self.rom
+    }
+
+    pub fn rom_mut(&mut self) -> &mut Rom {
+        &mut self.rom
+    }
+
+    pub fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+
+    // reads the currently mapped byte without any side effect on the cartridge
+    pub fn peek(&self, addr: u16) -> GBResult<u8> {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL => match self.flash.as_ref().and_then(|flash| flash.read(addr)) {
+                Some(id) => Ok(id),
+                None => Ok(self.mbc.read_rom(&self.rom, addr)),
+            },
+            EXTERNAL_RAM_ADDR_TOP..=EXTERNAL_RAM_ADDR_TAIL => Ok(self.mbc.read_ram(&self.ram, addr)),
+            _ => Err(GBError::InvalidAddress(addr)),
+        }
+    }
+
+    // reads as the cpu does, which moves on mappers that react to reads
+    pub fn read_mut(&mut self, addr: u16) -> GBResult<u8> {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL => match self.flash.as_ref().and_then(|flash| flash.read(addr)) {
+                Some(id) => Ok(id),
+                None => Ok(self.mbc.read_rom_mut(&self.rom, addr)),
+            },
+            _ => self.peek(addr),
+        }
+    }
+
+    // writes into the rom or ram currently mapped at addr instead of the mbc registers
+    pub fn poke(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        let ok = match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL if !self.rom.is_empty() => {
+                let offset = self.mbc.rom_offset(addr) % self.rom.len();
+                self.rom.set(offset, val)
+            },
+            EXTERNAL_RAM_ADDR_TOP..=EXTERNAL_RAM_ADDR_TAIL if !self.ram.is_empty() => match self.mbc.ram_offset(addr) {
+                Some(offset) => {
+                    let len = self.ram.len();
+                    self.ram.set(offset % len, val)
+                },
+                None => false,
+            },
+            _ => false,
+        };
+        if ok { Ok(()) } else { Err(GBError::InvalidAddress(addr)) }
+    }
+}
+
+impl<'a> Device for Cartridge<'a> {
+    fn read(&self, addr: u16) -> GBResult<u8> {
+        self.peek(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL => {
+                // the flash sees the address through the banks mapped before the write
+                if let Some(flash) = &mut self.flash {
+                    if !self.rom.is_empty() {
+                        let offset = self.mbc.rom_offset(addr) % self.rom.len();
+                        flash.write(&mut self.rom, addr, offset, val);
+                    }
+                }
+                let rumble = self.mbc.rumble();
+                self.mbc.write_register(addr, val);
+                self.mark_dirty(false);
+                if self.mbc.rumble() != rumble {
+                    if let Some(RumbleHandler(f)) = &mut self.rumble_handler {
+                        f(!rumble);
+                    }
+                }
+                Ok(())
+            },
+            EXTERNAL_RAM_ADDR_TOP..=EXTERNAL_RAM_ADDR_TAIL => {
+                // writes to disabled ram leave the contents alone
+                self.ram.take_changed();
+                self.mbc.write_ram(&mut self.ram, addr, val);
+                let changed = self.ram.take_changed();
+                self.mark_dirty(changed);
+                Ok(())
+            },
+            _ => Err(GBError::InvalidAddress(addr)),
         }
     }
 }
@@ -0,0 +1,155 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::*;
+use super::Cartridge;
+use super::mbc3::CPU_CLOCK_HZ;
+
+const SAVE_EXTENSION: &str = "sav";
+const TEMP_EXTENSION: &str = "tmp";
+
+pub fn save_path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension(SAVE_EXTENSION)
+}
+
+// writes a temporary file next to path and renames it over path,
+// so a crash in the middle leaves either the old or the new file
+pub fn write_atomic(path: &Path, data: &[u8]) -> GBResult<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".");
+    temp.push(TEMP_EXTENSION);
+    let temp = PathBuf::from(temp);
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+// the battery backed ram of a cartridge mirrored to a .sav file
+#[derive(Debug)]
+pub struct SaveFile {
+    path: PathBuf,
+    interval: u32, // cycles between flushes
+    cycles: u32,
+}
+
+impl SaveFile {
+    pub fn new(path: PathBuf) -> SaveFile {
+        SaveFile {
+            path,
+            interval: CPU_CLOCK_HZ,
+            cycles: 0u32,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_interval(&mut self, cycles: u32) {
+        self.interval = cycles;
+    }
+
+    // false when there is no save yet
+    pub fn load(&self, cart: &mut Cartridge) -> GBResult<bool> {
+        if !cart.has_battery() {
+            return Ok(false);
+        }
+        match fs::read(&self.path) {
+            Ok(data) => {
+                cart.load_save_data(&data);
+                Ok(true)
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // writes the save if the ram changed since the last flush, call it on shutdown too
+    pub fn flush(&mut self, cart: &mut Cartridge) -> GBResult<bool> {
+        self.cycles = 0;
+        if !cart.take_ram_dirty() {
+            return Ok(false);
+        }
+        write_atomic(&self.path, &cart.save_data())?;
+        Ok(true)
+    }
+
+    pub fn tick(&mut self, cart: &mut Cartridge, cycles: u32) -> GBResult<bool> {
+        self.cycles = self.cycles.saturating_add(cycles);
+        if self.cycles < self.interval {
+            return Ok(false);
+        }
+        self.flush(cart)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::device::Device;
+    use crate::mem::ram::Ram;
+    use crate::mem::rom::Rom;
+
+    #[test]
+    fn test_save_file() {
+        let dir = std::env::temp_dir().join(format!("rusgb-save-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = save_path(&dir.join("game.gb"));
+        assert_eq!(path, dir.join("game.sav"));
+
+        let mut rom = Rom::new(test_rom(0x03, 0x00, 0x02));
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        let mut save = SaveFile::new(path.clone());
+        assert!(!save.load(&mut cart).unwrap());
+        cart.write(0x0000, 0x0a).unwrap();
+        cart.write(0xa010, 0x42).unwrap();
+        assert!(!save.tick(&mut cart, CPU_CLOCK_HZ - 1).unwrap());
+        assert!(save.tick(&mut cart, 1).unwrap());
+        assert!(!save.flush(&mut cart).unwrap());
+        assert_eq!(fs::read(&path).unwrap()[0x10], 0x42);
+
+        let mut rom = Rom::new(test_rom(0x03, 0x00, 0x02));
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        assert!(save.load(&mut cart).unwrap());
+        assert_eq!(cart.ram().get(0x10), Some(0x42));
+        assert!(!cart.take_ram_dirty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ram_dirty() {
+        let mut cart = Cartridge::owned(Rom::new(test_rom(0x10, 0x00, 0x02)), Ram::new(vec![])).unwrap();
+        // disabled ram and unchanged bytes do not need saving
+        cart.write(0xa000, 0x42).unwrap();
+        cart.write(0x0000, 0x0a).unwrap();
+        cart.write(0xa000, 0x00).unwrap();
+        assert!(!cart.take_ram_dirty());
+        cart.write(0xa000, 0x42).unwrap();
+        assert!(cart.take_ram_dirty());
+        assert!(!cart.take_ram_dirty());
+
+        // the rtc is saved in the footer, setting or latching it needs saving too
+        cart.write(0x4000, 0x08).unwrap();
+        cart.write(0xa000, 0x10).unwrap();
+        assert!(cart.take_ram_dirty());
+        cart.write(0x6000, 0x00).unwrap();
+        assert!(!cart.take_ram_dirty());
+        cart.write(0x6000, 0x01).unwrap();
+        assert!(cart.take_ram_dirty());
+
+        // a clock without ram is saved all the same
+        let mut cart = Cartridge::owned(Rom::new(test_rom(0x0f, 0x00, 0x00)), Ram::new(vec![])).unwrap();
+        cart.write(0x0000, 0x0a).unwrap();
+        cart.write(0x4000, 0x0c).unwrap();
+        cart.write(0xa000, 0x40).unwrap();
+        assert!(cart.take_ram_dirty());
+        assert!(!cart.save_data().is_empty());
+    }
+}
@@ -0,0 +1,489 @@
+use crate::mem::rom::Rom;
+use crate::mem::*;
+use super::*;
+use super::mbc5::Mbc5;
+
+const WISDOM_TREE_SIZE: usize = 0x8000;
+const M161_ROM_SIZE: usize = 0x40000;
+
+// the boot rom checks the logo at 0x0184 while a sachen cartridge is locked
+const SACHEN_LOGO_ADDR: usize = LOGO_ADDR | 0x80;
+// reads of the logo until the lock of a sachen mapper moves on, the next read is the first after it
+const SACHEN_LOCK_READS: u8 = 0x31;
+
+// bit i of the result is taken from bit table[i], unknown modes are left unchanged
+const BBD_DATA_ORDER: [[u8; 8]; 8] = [
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 5, 1, 3, 4, 2, 6, 7],
+    [0, 4, 2, 3, 1, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 5, 3, 4, 2, 6, 7],
+];
+
+const BBD_BANK_ORDER: [[u8; 8]; 8] = [
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [3, 4, 2, 0, 1, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [1, 2, 3, 4, 0, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+];
+
+const HITEK_DATA_ORDER: [[u8; 8]; 8] = [
+    [7, 6, 5, 4, 3, 2, 1, 0],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [1, 0, 2, 3, 4, 5, 6, 7],
+    [6, 1, 2, 3, 4, 5, 0, 7],
+    [0, 6, 2, 3, 4, 5, 1, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+];
+
+const HITEK_BANK_ORDER: [[u8; 8]; 8] = [
+    [7, 6, 5, 4, 3, 2, 1, 0],
+    [3, 2, 1, 0, 7, 6, 5, 4],
+    [2, 1, 0, 4, 3, 5, 6, 7],
+    [6, 5, 4, 3, 2, 1, 0, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [0, 1, 2, 3, 4, 5, 6, 7],
+];
+
+// guesses the mapper of unlicensed cartridges whose header names a licensed one or nothing at all
+pub fn detect(rom: &Rom) -> Option<MbcKind> {
+    let byte = |addr: usize| rom.get(addr).unwrap_or(0);
+    let code = byte(CARTRIDGE_TYPE_ADDR);
+    let logo = |top: usize| (0..NINTENDO_LOGO.len()).all(|i| byte(top + i) == NINTENDO_LOGO[i]);
+
+    if !logo(LOGO_ADDR) && (0..NINTENDO_LOGO.len()).all(|i| byte(Sachen::header_addr(SACHEN_LOGO_ADDR + i)) == NINTENDO_LOGO[i]) {
+        return match byte(Sachen::header_addr(CGB_FLAG_ADDR)) & 0x80 {
+            0 => Some(MbcKind::SachenMmc1),
+            _ => Some(MbcKind::SachenMmc2),
+        };
+    }
+    if code == 0x00 && rom.len() > WISDOM_TREE_SIZE && (contains(rom, b"WISDOM TREE") || contains(rom, b"WISDOM\x00TREE")) {
+        return Some(MbcKind::WisdomTree);
+    }
+    if code == 0x10 && byte(RAM_SIZE_ADDR) == 0x00 && rom.len() == M161_ROM_SIZE {
+        return Some(MbcKind::M161);
+    }
+    // the writes below are ordinary bank switches on mbc5, they are only looked for in bootlegs
+    // which name no licensee or whose global checksum does not add up, licensed games keep both
+    let global = u16::from_be_bytes([byte(GLOBAL_CHECKSUM_ADDR), byte(GLOBAL_CHECKSUM_ADDR + 1)]);
+    if !(0x19..=0x1e).contains(&code) || (byte(OLD_LICENSEE_CODE_ADDR) != 0x00 && global_checksum(rom) == global) {
+        return None;
+    }
+    // hitek games set up a mode which is scrambled on hitek and plain on bbd with ld a,n; ld (0x2001),a or ld (0x2080),a
+    let hitek = |register: u8, hitek: &[[u8; 8]; 8], bbd: &[[u8; 8]; 8]| {
+        (0..8).filter(|n| hitek[*n] != BBD_DATA_ORDER[1] && bbd[*n] == BBD_DATA_ORDER[1])
+            .any(|n| contains(rom, &[0x3e, n as u8, 0xea, register, 0x20]))
+    };
+    if hitek(0x01, &HITEK_DATA_ORDER, &BBD_DATA_ORDER) || hitek(0x80, &HITEK_BANK_ORDER, &BBD_BANK_ORDER) {
+        return Some(MbcKind::Hitek);
+    }
+    // BBD games set up their scrambling with ld (0x2080),a and ld (0x2001),a in bank 0
+    if contains(rom, &[0xea, 0x80, 0x20]) && contains(rom, &[0xea, 0x01, 0x20]) {
+        return Some(MbcKind::Bbd);
+    }
+    None
+}
+
+// looks for the pattern in bank 0
+fn contains(rom: &Rom, pattern: &[u8]) -> bool {
+    let len = rom.len().min(ROM_BANK_SIZE);
+    (0..len.saturating_sub(pattern.len())).any(|top| (0..pattern.len()).all(|i| rom.get(top + i) == Some(pattern[i])))
+}
+
+fn reorder(val: u8, order: &[u8; 8]) -> u8 {
+    order.iter().enumerate().fold(0u8, |x, (i, bit)| x | (((val >> bit) & 0x01) << i))
+}
+
+// a single 32KB bank picked by the low byte of the address written to 0x0000-0x3fff
+#[derive(Debug)]
+pub struct WisdomTree {
+    bank: u8,
+}
+
+impl WisdomTree {
+    pub fn new() -> WisdomTree {
+        WisdomTree {
+            bank: 0u8,
+        }
+    }
+}
+
+impl Mapper for WisdomTree {
+    fn rom_offset(&self, addr: u16) -> usize {
+        self.bank as usize * WISDOM_TREE_SIZE + addr as usize
+    }
+
+    fn write_register(&mut self, addr: u16, _val: u8) {
+        if let 0x0000..=0x3fff = addr {
+            self.bank = addr as u8;
+        }
+    }
+}
+
+impl Default for WisdomTree {
+    fn default() -> Self {
+        WisdomTree::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SachenLock {
+    NintendoLogo, // mmc2 shows the nintendo logo to the first check of a cgb
+    SachenLogo, // reads of 0x0100-0x01ff are moved to 0x0180-0x01ff
+    Unlocked,
+}
+
+// the logo lock is released by the boot rom reading the logo, 0x0100-0x01ff reads have the
+// address lines 0 and 6 and 1 and 4 swapped
+#[derive(Debug)]
+pub struct Sachen {
+    mmc2: bool,
+    base: u8,
+    bank: u8,
+    mask: u8, // bits of the rom bank taken from base
+    lock: SachenLock,
+    reads: u8, // logo reads in the current lock
+}
+
+impl Sachen {
+    // locked as at power on, for a boot rom to unlock
+    pub fn new(mmc2: bool) -> Sachen {
+        let lock = if mmc2 { SachenLock::NintendoLogo } else { SachenLock::SachenLogo };
+        Sachen {
+            mmc2,
+            base: 0u8,
+            bank: 1u8,
+            mask: 0u8,
+            lock,
+            reads: 0u8,
+        }
+    }
+
+    // as the boot rom leaves it, the boot rom is not emulated
+    pub fn unlocked(mmc2: bool) -> Sachen {
+        let mut mbc = Sachen::new(mmc2);
+        mbc.lock = SachenLock::Unlocked;
+        mbc
+    }
+
+    pub fn lock(&self) -> SachenLock {
+        self.lock
+    }
+
+    // rom offset of a header address in the unlocked view
+    pub fn header_addr(addr: usize) -> usize {
+        match addr & 0xff00 {
+            0x0100 => (addr & 0xffac) | (addr & 0x40) >> 6 | (addr & 0x10) >> 3 | (addr & 0x02) << 3 | (addr & 0x01) << 6,
+            _ => addr,
+        }
+    }
+
+    fn count_read(&mut self, addr: u16) {
+        // mmc2 decodes fewer address lines and also counts the mirrors of 0x0100-0x01ff
+        let logo = if self.mmc2 { addr & 0x8700 == 0x0100 } else { addr & 0xff00 == 0x0100 };
+        if self.lock == SachenLock::Unlocked || !logo {
+            return;
+        }
+        self.reads += 1;
+        if self.reads == SACHEN_LOCK_READS {
+            self.reads = 0;
+            self.lock = match self.lock {
+                SachenLock::NintendoLogo => SachenLock::SachenLogo,
+                _ => SachenLock::Unlocked,
+            };
+        }
+    }
+}
+
+impl Mapper for Sachen {
+    fn rom_offset(&self, addr: u16) -> usize {
+        let bank = match addr as usize {
+            ROM_ADDR_TOP..=ROM_ADDR_TAIL => self.base & self.mask,
+            _ => (self.bank & !self.mask) | (self.base & self.mask),
+        };
+        bank as usize * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        // base and mask are only writable while the outer bank bits are set
+        let outer = self.bank & 0x30 == 0x30;
+        match addr {
+            0x0000..=0x1fff if outer => self.base = val,
+            0x2000..=0x3fff => self.bank = val.max(1),
+            0x4000..=0x5fff if outer => self.mask = val,
+            // mmc2 moves on to the sachen logo early, and unlocks when enough of it was read
+            0x6000..=0x7fff if self.mmc2 => match self.lock {
+                SachenLock::NintendoLogo => {
+                    self.lock = SachenLock::SachenLogo;
+                    self.reads = 0;
+                },
+                SachenLock::SachenLogo if self.reads & 0x30 == 0x30 => self.lock = SachenLock::Unlocked,
+                _ => {},
+            },
+            _ => {},
+        }
+    }
+
+    fn read_rom(&self, rom: &Rom, addr: u16) -> u8 {
+        let mut addr = addr;
+        if self.lock == SachenLock::SachenLogo && addr & 0xff00 == 0x0100 {
+            addr |= 0x80;
+        }
+        read_mapped_rom(self, rom, Sachen::header_addr(addr as usize) as u16)
+    }
+
+    // the read which releases the lock already sees the unlocked view
+    fn read_rom_mut(&mut self, rom: &Rom, addr: u16) -> u8 {
+        self.count_read(addr);
+        self.read_rom(rom, addr)
+    }
+}
+
+// a collection mapper which maps one 32KB game at the first write and ignores everything after
+#[derive(Debug)]
+pub struct M161 {
+    bank: u8,
+    locked: bool,
+}
+
+impl M161 {
+    pub fn new() -> M161 {
+        M161 {
+            bank: 0u8,
+            locked: false,
+        }
+    }
+}
+
+impl Mapper for M161 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        self.bank as usize * 2 * ROM_BANK_SIZE + addr as usize
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        if let 0x4000..=0x5fff = addr {
+            if !self.locked {
+                self.bank = val & 0x07;
+                self.locked = true;
+            }
+        }
+    }
+}
+
+impl Default for M161 {
+    fn default() -> Self {
+        M161::new()
+    }
+}
+
+// an MBC5 clone which scrambles the rom bank number and the data read from 0x4000-0x7fff
+#[derive(Debug)]
+pub struct Bbd {
+    mbc5: Mbc5,
+    hitek: bool,
+    data_mode: u8,
+    bank_mode: u8,
+}
+
+impl Bbd {
+    pub fn new(hitek: bool) -> Bbd {
+        Bbd {
+            mbc5: Mbc5::new(false),
+            hitek,
+            data_mode: 0u8,
+            bank_mode: 0u8,
+        }
+    }
+
+    fn data_order(&self) -> &'static [u8; 8] {
+        let table = if self.hitek { &HITEK_DATA_ORDER } else { &BBD_DATA_ORDER };
+        &table[self.data_mode as usize]
+    }
+
+    fn bank_order(&self) -> &'static [u8; 8] {
+        let table = if self.hitek { &HITEK_BANK_ORDER } else { &BBD_BANK_ORDER };
+        &table[self.bank_mode as usize]
+    }
+}
+
+impl Mapper for Bbd {
+    fn rom_offset(&self, addr: u16) -> usize {
+        self.mbc5.rom_offset(addr)
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        self.mbc5.ram_offset(addr)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr & 0xf0ff {
+            0x2000 => self.mbc5.write_register(addr, reorder(val, self.bank_order())),
+            0x2001 => self.data_mode = val & 0x07,
+            0x2080 => self.bank_mode = val & 0x07,
+            _ => self.mbc5.write_register(addr, val),
+        }
+    }
+
+    fn read_rom(&self, rom: &Rom, addr: u16) -> u8 {
+        let val = read_mapped_rom(self, rom, addr);
+        match addr as usize {
+            ROM_BANK_ADDR_TOP..=ROM_BANK_ADDR_TAIL => reorder(val, self.data_order()),
+            _ => val,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::mem::ram::Ram;
+
+    // the header of test_rom and the logo at 0x0184, at their scrambled offsets
+    fn sachen_rom(cgb_flag: u8) -> Vec<u8> {
+        let mut header = test_rom(0x00, 0x01, 0x00);
+        header[CGB_FLAG_ADDR] = cgb_flag;
+        header[SACHEN_LOGO_ADDR..SACHEN_LOGO_ADDR + 0x30].copy_from_slice(&NINTENDO_LOGO);
+        crate::cartridge::header::fix_checksums(&mut header);
+        let mut v = header.clone();
+        for addr in 0x0100..0x0200 {
+            v[Sachen::header_addr(addr)] = header[addr];
+        }
+        v
+    }
+
+    #[test]
+    fn test_detect() {
+        let mut v = test_rom(0x00, 0x01, 0x00);
+        v[0x3000..0x300b].copy_from_slice(b"WISDOM TREE");
+        assert_eq!(detect(&Rom::new(v)), Some(MbcKind::WisdomTree));
+
+        let mut v = sachen_rom(0x00);
+        assert_eq!(detect(&Rom::new(v.clone())), Some(MbcKind::SachenMmc1));
+        assert_eq!(detect(&Rom::new(sachen_rom(0x80))), Some(MbcKind::SachenMmc2));
+        v[LOGO_ADDR..LOGO_ADDR + 0x30].copy_from_slice(&NINTENDO_LOGO);
+        assert_eq!(detect(&Rom::new(v)), None);
+
+        assert_eq!(detect(&Rom::new(test_rom(0x10, 0x03, 0x00))), Some(MbcKind::M161));
+        assert_eq!(detect(&Rom::new(test_rom(0x10, 0x03, 0x03))), None);
+
+        let mut v = test_rom(0x19, 0x01, 0x00);
+        v[0x200..0x206].copy_from_slice(&[0xea, 0x80, 0x20, 0xea, 0x01, 0x20]);
+        assert_eq!(detect(&Rom::new(v.clone())), Some(MbcKind::Bbd));
+        v[0x300..0x305].copy_from_slice(&[0x3e, 0x05, 0xea, 0x01, 0x20]);
+        assert_eq!(detect(&Rom::new(v)), Some(MbcKind::Bbd));
+
+        // data mode 2 swaps bits on hitek only
+        let mut v = test_rom(0x1b, 0x01, 0x02);
+        v[0x200..0x205].copy_from_slice(&[0x3e, 0x02, 0xea, 0x01, 0x20]);
+        assert_eq!(detect(&Rom::new(v.clone())), Some(MbcKind::Hitek));
+
+        // a licensed mbc5 game switching banks through the mirrored registers
+        v[0x300..0x306].copy_from_slice(&[0xea, 0x80, 0x20, 0xea, 0x01, 0x20]);
+        v[OLD_LICENSEE_CODE_ADDR] = 0x01;
+        fix_checksums(&mut v);
+        assert_eq!(detect(&Rom::new(v.clone())), None);
+        let mut rom = Rom::new(v);
+        let mut ram = Ram::new(vec![]);
+        let cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        assert_eq!(cart.header().cartridge_type.mbc, MbcKind::Mbc5);
+    }
+
+    #[test]
+    fn test_wisdom_tree_and_m161() {
+        let mut v = test_rom(0x00, 0x02, 0x00);
+        v[0x3000..0x300b].copy_from_slice(b"WISDOM TREE");
+        v[0x10000] = 0x42;
+        let mut rom = Rom::new(v);
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        assert_eq!(cart.header().cartridge_type.mbc, MbcKind::WisdomTree);
+        cart.write(0x0002, 0x00).unwrap();
+        assert_eq!(cart.read(0x0000).unwrap(), 0x42);
+
+        let mut mbc = M161::new();
+        mbc.write_register(0x4000, 0x03);
+        mbc.write_register(0x4000, 0x05);
+        assert_eq!(mbc.rom_offset(0x4000), 0x03 * 0x8000 + 0x4000);
+    }
+
+    #[test]
+    fn test_sachen() {
+        let mut mbc = Sachen::unlocked(false);
+        // base and mask are ignored until the outer bank bits are set
+        mbc.write_register(0x0000, 0x10);
+        mbc.write_register(0x2000, 0x30);
+        mbc.write_register(0x0000, 0x10);
+        mbc.write_register(0x4000, 0x30);
+        mbc.write_register(0x2000, 0x03);
+        assert_eq!(mbc.rom_offset(0x0000), 0x10 * ROM_BANK_SIZE);
+        assert_eq!(mbc.rom_offset(0x4000), 0x13 * ROM_BANK_SIZE);
+    }
+
+    #[test]
+    fn test_sachen_lock() {
+        let rom = Rom::new(sachen_rom(0x00));
+        // mmc1 shows the logo at 0x0184 to the first 0x30 reads of 0x0100-0x01ff
+        let mut mbc = Sachen::new(false);
+        for (i, val) in NINTENDO_LOGO.iter().enumerate() {
+            assert_eq!(mbc.read_rom_mut(&rom, 0x0104 + i as u16), *val);
+        }
+        assert_eq!(mbc.lock(), SachenLock::SachenLogo);
+        // peeking does not count
+        mbc.read_rom(&rom, TITLE_ADDR as u16);
+        assert_eq!(mbc.lock(), SachenLock::SachenLogo);
+        assert_eq!(mbc.read_rom_mut(&rom, TITLE_ADDR as u16), b'T');
+        assert_eq!(mbc.lock(), SachenLock::Unlocked);
+
+        // mmc2 shows the nintendo logo first, then the one at 0x0184
+        let mut mbc = Sachen::new(true);
+        for _ in 0..0x30 {
+            assert_eq!(mbc.read_rom_mut(&rom, 0x0104), 0x00);
+        }
+        assert_eq!(mbc.read_rom_mut(&rom, 0x0104), NINTENDO_LOGO[0]);
+        mbc.write_register(0x6000, 0x00);
+        assert_eq!(mbc.lock(), SachenLock::SachenLogo);
+        for _ in 0..0x30 {
+            mbc.read_rom_mut(&rom, 0x0104);
+        }
+        mbc.write_register(0x6000, 0x00);
+        assert_eq!(mbc.lock(), SachenLock::Unlocked);
+
+        // the header only adds up in the unlocked view
+        let mut rom = Rom::new(sachen_rom(0x00));
+        assert!(CartridgeHeader::parse(&rom).is_err());
+        let mut ram = Ram::new(vec![]);
+        let cart = Cartridge::new(&mut rom, &mut ram).unwrap();
+        assert_eq!((cart.header().cartridge_type.mbc, cart.header().title.as_str()), (MbcKind::SachenMmc1, "TEST"));
+        assert_eq!(cart.read(TITLE_ADDR as u16).unwrap(), b'T');
+    }
+
+    #[test]
+    fn test_bbd() {
+        let mut v = test_rom(0x19, 0x03, 0x00);
+        v[0x4000] = 0b0000_0010;
+        v[0x0000] = 0b0000_0010;
+        let mut rom = Rom::new(v);
+        let mut ram = Ram::new(vec![]);
+        let mut cart = Cartridge::with_mbc(&mut rom, &mut ram, MbcKind::Bbd).unwrap();
+        cart.write(0x2001, 0x05).unwrap();
+        assert_eq!(cart.read(0x0000).unwrap(), 0b0000_0010);
+        assert_eq!(cart.read(0x4000).unwrap(), 0b0001_0000);
+
+        // bank 3 is written as bank 0x18 in mode 3
+        cart.write(0x2080, 0x03).unwrap();
+        cart.write(0x2000, 0x18).unwrap();
+        assert_eq!(cart.mbc().rom_offset(0x4000), 0x03 * ROM_BANK_SIZE);
+    }
+}
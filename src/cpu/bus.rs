@@ -1,6 +1,23 @@
-use crate::cartridge::Cartridge;
+use std::ops::RangeInclusive;
+
+use crate::cartridge::*;
+use crate::device::Device;
+use crate::error::*;
+use crate::mem::*;
 use crate::mem::ram::Ram;
-use crate::timer::Timer;
+use crate::ppu::*;
+use crate::scheduler::{Event, Scheduler};
+use crate::timer::*;
+use super::hook::*;
+use super::oam_bug::{self, OamBug};
+
+// banked memories that tools can address directly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Rom,
+    ExternalRam,
+    Vram,
+}
 
 #[derive(Debug)]
 pub struct Bus<'a> {
@@ -8,15 +25,531 @@ pub struct Bus<'a> {
     ram: &'a mut Ram,
     hram: &'a mut Ram,
     timer: &'a mut Timer,
+    timer_synced: u64, // when the timer was last run
+    cartridge_synced: u64,
+    ppu: Ppu,
+    ppu_synced: u64,
+    scheduler: Scheduler,
+    access_lock: bool,
+    oam_bug: bool,
+    interrupt_flag: u8,
+    interrupt_enable: u8,
+    hooks: Hooks,
 }
 
 impl<'a> Bus<'a> {
     pub fn new(ram: &'a mut Ram, hram: &'a mut Ram, cart: &'a mut Cartridge<'a>, timer: &'a mut Timer) -> Bus<'a> {
-        Bus {
+        // cgb cartridges run in cgb mode which has no oam bug
+        let oam_bug = !cart.header().is_cgb();
+        let mut ppu = Ppu::new();
+        ppu.set_cgb(cart.header().is_cgb());
+        let mut bus = Bus {
             cartridge: cart,
             ram: ram,
             hram: hram,
             timer: timer,
+            timer_synced: 0u64,
+            cartridge_synced: 0u64,
+            ppu,
+            ppu_synced: 0u64,
+            scheduler: Scheduler::new(),
+            access_lock: true,
+            oam_bug,
+            interrupt_flag: 0u8,
+            interrupt_enable: 0u8,
+            hooks: Hooks::new(),
+        };
+        bus.schedule_timer();
+        bus
+    }
+
+    pub fn read(&mut self, addr: u16) -> GBResult<u8> {
+        self.sync_cartridge_for(addr, false);
+        self.sync_ppu_for(addr);
+        let val = if self.is_locked(addr) { 0xff } else { self.read_cpu(addr)? };
+        if !self.hooks.is_empty() {
+            self.hooks.dispatch(Access { kind: AccessKind::Read, addr, val });
+        }
+        Ok(val)
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        self.sync_cartridge_for(addr, true);
+        self.sync_ppu_for(addr);
+        if !self.is_locked(addr) {
+            self.write_inner(addr, val)?;
+        }
+        if !self.hooks.is_empty() {
+            self.hooks.dispatch(Access { kind: AccessKind::Write, addr, val });
+        }
+        Ok(())
+    }
+
+    // opcode fetch
+    pub fn fetch(&mut self, addr: u16) -> GBResult<u8> {
+        self.sync_cartridge_for(addr, false);
+        self.sync_ppu_for(addr);
+        let val = if self.is_locked(addr) { 0xff } else { self.read_cpu(addr)? };
+        if !self.hooks.is_empty() {
+            self.hooks.dispatch(Access { kind: AccessKind::Execute, addr, val });
+        }
+        Ok(val)
+    }
+
+    // advances the clock by the cpu cycles an instruction consumed and handles the events which
+    // are due. the components only run at their events or when the cpu accesses them
+    pub fn tick(&mut self, cycles: usize) {
+        self.advance(cycles);
+        self.handle_events();
+    }
+
+    // advances the clock without running anything, the events have to be handled once one is due
+    pub fn advance(&mut self, cycles: usize) {
+        self.scheduler.advance(cycles as u64);
+    }
+
+    pub fn event_due(&self) -> bool {
+        self.scheduler.cycles_until_next() == Some(0)
+    }
+
+    pub fn handle_events(&mut self) {
+        while let Some(event) = self.scheduler.pop_due() {
+            match event {
+                Event::Timer => self.sync_timer(),
+                Event::Ppu => self.sync_ppu(),
+            }
+        }
+    }
+
+    // runs every component up to now, e.g. before the frontend looks at the cartridge
+    pub fn sync(&mut self) {
+        self.sync_cartridge();
+        self.sync_timer();
+        self.sync_ppu();
+    }
+
+    fn sync_cartridge(&mut self) {
+        let now = self.scheduler.now();
+        let mut cycles = now - self.cartridge_synced;
+        while cycles > 0 {
+            let n = cycles.min(u32::MAX as u64);
+            self.cartridge.tick(n as u32);
+            cycles -= n;
+        }
+        self.cartridge_synced = now;
+    }
+
+    // the ram area holds the clocks and sensors of the mappers, writes to the rom area latch or start them
+    fn sync_cartridge_for(&mut self, addr: u16, write: bool) {
+        match addr as usize {
+            EXTERNAL_RAM_ADDR_TOP..=EXTERNAL_RAM_ADDR_TAIL => self.sync_cartridge(),
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL if write => self.sync_cartridge(),
+            _ => {},
+        }
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    // runs the timer up to now
+    fn sync_timer(&mut self) {
+        let now = self.scheduler.now();
+        if self.timer.tick((now - self.timer_synced) as usize) {
+            self.interrupt_flag |= TIMER_INTERRUPT;
+        }
+        self.timer_synced = now;
+        self.schedule_timer();
+    }
+
+    fn schedule_timer(&mut self) {
+        match self.timer.cycles_until_interrupt() {
+            Some(cycles) => self.scheduler.schedule(Event::Timer, cycles),
+            None => self.scheduler.cancel(Event::Timer),
+        }
+    }
+
+    // the ppu only changes at its events, so it is not run for reads
+    fn sync_ppu(&mut self) {
+        let now = self.scheduler.now();
+        self.interrupt_flag |= self.ppu.tick((now - self.ppu_synced) as usize);
+        self.ppu_synced = now;
+        self.schedule_ppu();
+    }
+
+    // the mode is fixed between events but the dot is not, so vram and oam accesses see
+    // the ppu as it is now
+    fn sync_ppu_for(&mut self, addr: u16) {
+        if (VRAM_ADDR_TOP..=VRAM_ADDR_TAIL).contains(&(addr as usize))
+            || (OAM_ADDR_TOP..=NOT_USABLE_ADDR_TAIL).contains(&(addr as usize))
+        {
+            self.sync_ppu();
+        }
+    }
+
+    fn schedule_ppu(&mut self) {
+        match self.ppu.cycles_until_event() {
+            Some(cycles) => self.scheduler.schedule(Event::Ppu, cycles),
+            None => self.scheduler.cancel(Event::Ppu),
+        }
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    // the timer as it is now for peek, which cannot run it. no interrupt is due before its event
+    fn timer_now(&self) -> Timer {
+        let mut timer = self.timer.clone();
+        timer.tick((self.scheduler.now() - self.timer_synced) as usize);
+        timer
+    }
+
+    pub fn lcd_mode(&self) -> LcdMode {
+        self.ppu.mode()
+    }
+
+    // forces the mode until the ppu changes it, for debugging
+    pub fn set_lcd_mode(&mut self, mode: LcdMode) {
+        self.ppu.set_mode(mode);
+    }
+
+    // disabling the lock gives the cpu access to vram and oam in every mode, for debugging
+    pub fn set_access_lock(&mut self, enable: bool) {
+        self.access_lock = enable;
+    }
+
+    // moves the ppu to the row of oam it reads in oam scan, for debugging
+    pub fn set_oam_scan_row(&mut self, row: usize) {
+        self.sync_ppu();
+        self.ppu.set_dot(row as u32 * 4);
+        self.schedule_ppu();
+    }
+
+    // oam corruption only happens on DMG
+    pub fn set_oam_bug(&mut self, enable: bool) {
+        self.oam_bug = enable;
+    }
+
+    // called by instructions for every cycle that puts addr on the bus with the inc/dec unit or a memory access
+    pub fn trigger_oam_bug(&mut self, addr: u16, kind: OamBug) {
+        if !self.oam_bug || !(OAM_ADDR_TOP..=NOT_USABLE_ADDR_TAIL).contains(&(addr as usize)) {
+            return;
+        }
+        // the row depends on the dot, which only moves when the ppu runs
+        self.sync_ppu();
+        if self.ppu.mode() == LcdMode::OamScan {
+            let row = self.ppu.oam_scan_row();
+            oam_bug::corrupt(self.ppu.oam_mut(), row, kind);
+        }
+    }
+
+    fn is_locked(&self, addr: u16) -> bool {
+        if !self.access_lock {
+            return false;
+        }
+        let mode = self.ppu.mode();
+        match addr as usize {
+            VRAM_ADDR_TOP..=VRAM_ADDR_TAIL => mode == LcdMode::Drawing,
+            OAM_ADDR_TOP..=OAM_ADDR_TAIL => mode == LcdMode::OamScan || mode == LcdMode::Drawing,
+            _ => false,
+        }
+    }
+
+    pub fn add_hook<F>(&mut self, kind: AccessKind, range: RangeInclusive<u16>, val: Option<u8>, f: F) -> HookId
+    where
+        F: FnMut(&Access) -> HookAction + 'static,
+    {
+        self.hooks.add(kind, range, val, Box::new(f))
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.hooks.remove(id)
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear()
+    }
+
+    // returns the access that requested a pause since the last call
+    pub fn take_pause(&mut self) -> Option<Access> {
+        self.hooks.take_pause()
+    }
+
+    // reads any address without side effects, hooks or consumed cycles
+    pub fn peek(&self, addr: u16) -> GBResult<u8> {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL => self.cartridge.peek(addr),
+            EXTERNAL_RAM_ADDR_TOP..=EXTERNAL_RAM_ADDR_TAIL => self.cartridge.peek(addr),
+            _ => self.read_inner(addr),
+        }
+    }
+
+    // writes the memory behind addr directly, rom included, bypassing mbc registers and hooks
+    pub fn poke(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL => self.cartridge.poke(addr, val),
+            EXTERNAL_RAM_ADDR_TOP..=EXTERNAL_RAM_ADDR_TAIL => self.cartridge.poke(addr, val),
+            _ => match addr {
+                // registers are set without the side effects of a cpu write, the events follow the new values
+                DIV_ADDR..=TAC_ADDR => {
+                    self.sync_timer();
+                    self.timer.set_register(addr, val)?;
+                    self.schedule_timer();
+                    Ok(())
+                },
+                LCDC_ADDR..=LYC_ADDR | BGP_ADDR..=WX_ADDR => {
+                    self.sync_ppu();
+                    self.ppu.set_register(addr, val)?;
+                    self.schedule_ppu();
+                    Ok(())
+                },
+                _ => self.write_inner(addr, val),
+            },
+        }
+    }
+
+    // offset is relative to the top of the bank
+    pub fn peek_bank(&self, region: Region, bank: u16, offset: u16) -> GBResult<u8> {
+        let index = Bus::bank_index(region, bank, offset)?;
+        let val = match region {
+            Region::Rom => self.cartridge.rom().get(index),
+            Region::ExternalRam => self.cartridge.ram().get(index),
+            Region::Vram => self.ppu.vram().get(index),
+        };
+        val.ok_or(GBError::InvalidBank(bank))
+    }
+
+    pub fn poke_bank(&mut self, region: Region, bank: u16, offset: u16, val: u8) -> GBResult<()> {
+        let index = Bus::bank_index(region, bank, offset)?;
+        let ok = match region {
+            Region::Rom => self.cartridge.rom_mut().set(index, val),
+            Region::ExternalRam => self.cartridge.ram_mut().set(index, val),
+            Region::Vram => self.ppu.vram_mut().set(index, val),
+        };
+        if ok { Ok(()) } else { Err(GBError::InvalidBank(bank)) }
+    }
+
+    fn bank_index(region: Region, bank: u16, offset: u16) -> GBResult<usize> {
+        let size = match region {
+            Region::Rom => ROM_BANK_SIZE,
+            Region::ExternalRam => RAM_BANK_SIZE,
+            Region::Vram => VRAM_BANK_SIZE,
+        };
+        if offset as usize >= size {
+            return Err(GBError::InvalidAddress(offset));
+        }
+        Ok(bank as usize * size + offset as usize)
+    }
+
+    // the side effects of a read, which peek leaves out
+    fn read_cpu(&mut self, addr: u16) -> GBResult<u8> {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL => self.cartridge.read_mut(addr),
+            _ => match addr {
+                // the timer catches up instead of a copy of it, a div polling loop would replay it every read
+                DIV_ADDR..=TAC_ADDR => {
+                    self.sync_timer();
+                    self.timer.read(addr)
+                },
+                _ => self.read_inner(addr),
+            },
+        }
+    }
+
+    fn read_inner(&self, addr: u16) -> GBResult<u8> {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL => self.cartridge.read(addr),
+            VRAM_ADDR_TOP..=VRAM_ADDR_TAIL => self.ppu.read(addr),
+            EXTERNAL_RAM_ADDR_TOP..=EXTERNAL_RAM_ADDR_TAIL => self.cartridge.read(addr),
+            WRAM_BANK_0_ADDR_TOP..=WRAM_BANK_1_ADDR_TAIL => self.ram.read(addr - WRAM_BANK_0_ADDR_TOP as u16),
+            ECHO_RAM_ADDR_TOP..=ECHO_RAM_ADDR_TAIL => self.ram.read(addr - ECHO_RAM_ADDR_TOP as u16),
+            OAM_ADDR_TOP..=OAM_ADDR_TAIL => self.ppu.read(addr),
+            NOT_USABLE_ADDR_TOP..=NOT_USABLE_ADDR_TAIL => Ok(0xff),
+            INTERRUPT_FLAG_REG_ADDR => Ok(self.interrupt_flag | 0b1110_0000),
+            HRAM_ADDR_TOP..=HRAM_ADDR_TAIL => self.hram.read(addr - HRAM_ADDR_TOP as u16),
+            INTERRUPT_ENABLE_REG_ADDR => Ok(self.interrupt_enable),
+            _ => match addr {
+                DIV_ADDR..=TAC_ADDR => self.timer_now().read(addr),
+                LCDC_ADDR..=LYC_ADDR | BGP_ADDR..=WX_ADDR | VRAM_BANK_ADDR => self.ppu.read(addr),
+                // not connected yet
+                _ => Ok(0xff),
+            },
+        }
+    }
+
+    fn write_inner(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        match addr as usize {
+            ROM_ADDR_TOP..=ROM_BANK_ADDR_TAIL => self.cartridge.write(addr, val),
+            VRAM_ADDR_TOP..=VRAM_ADDR_TAIL => self.ppu.write(addr, val),
+            EXTERNAL_RAM_ADDR_TOP..=EXTERNAL_RAM_ADDR_TAIL => self.cartridge.write(addr, val),
+            WRAM_BANK_0_ADDR_TOP..=WRAM_BANK_1_ADDR_TAIL => self.ram.write(addr - WRAM_BANK_0_ADDR_TOP as u16, val),
+            ECHO_RAM_ADDR_TOP..=ECHO_RAM_ADDR_TAIL => self.ram.write(addr - ECHO_RAM_ADDR_TOP as u16, val),
+            OAM_ADDR_TOP..=OAM_ADDR_TAIL => self.ppu.write(addr, val),
+            NOT_USABLE_ADDR_TOP..=NOT_USABLE_ADDR_TAIL => Ok(()),
+            INTERRUPT_FLAG_REG_ADDR => {
+                self.interrupt_flag = val & 0b0001_1111;
+                Ok(())
+            },
+            HRAM_ADDR_TOP..=HRAM_ADDR_TAIL => self.hram.write(addr - HRAM_ADDR_TOP as u16, val),
+            INTERRUPT_ENABLE_REG_ADDR => {
+                self.interrupt_enable = val;
+                Ok(())
+            },
+            _ => match addr {
+                // a write changes when the timer overflows
+                DIV_ADDR..=TAC_ADDR => {
+                    self.sync_timer();
+                    self.timer.write(addr, val)?;
+                    self.schedule_timer();
+                    Ok(())
+                },
+                VRAM_BANK_ADDR => self.ppu.write(addr, val),
+                // a write can change the mode, ly or the stat interrupt line
+                LCDC_ADDR..=LYC_ADDR | BGP_ADDR..=WX_ADDR => {
+                    self.sync_ppu();
+                    self.ppu.write(addr, val)?;
+                    self.sync_ppu();
+                    Ok(())
+                },
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_bus_hooks() {
+        test_bus!(bus);
+
+        let reads = Rc::new(Cell::new(0));
+        let r = reads.clone();
+        bus.add_hook(AccessKind::Read, 0xc000..=0xdfff, None, move |_| {
+            r.set(r.get() + 1);
+            HookAction::Continue
+        });
+        let id = bus.add_hook(AccessKind::Write, 0xff80..=0xff80, Some(0x12), |_| HookAction::Pause);
+
+        bus.write(0xc001, 0x34).unwrap();
+        // echo ram mirrors wram but is outside the watched range
+        assert_eq!(bus.read(0xe001).unwrap(), 0x34);
+        assert_eq!(reads.get(), 0);
+        assert_eq!(bus.read(0xc001).unwrap(), 0x34);
+        assert_eq!(reads.get(), 1);
+
+        bus.write(0xff80, 0x11).unwrap();
+        assert_eq!(bus.take_pause(), None);
+        bus.write(0xff80, 0x12).unwrap();
+        assert_eq!(bus.take_pause(), Some(Access { kind: AccessKind::Write, addr: 0xff80, val: 0x12 }));
+
+        assert!(bus.remove_hook(id));
+        bus.write(0xff80, 0x12).unwrap();
+        assert_eq!(bus.take_pause(), None);
+    }
+
+    #[test]
+    fn test_bus_peek_poke() {
+        test_bus!(bus, test_rom(0x08, 0x00, 0x02));
+        bus.add_hook(AccessKind::Read, 0x0000..=0xffff, None, |_| HookAction::Pause);
+        bus.add_hook(AccessKind::Write, 0x0000..=0xffff, None, |_| HookAction::Pause);
+
+        // rom is writable only through poke
+        bus.poke(0x4000, 0xaa).unwrap();
+        assert_eq!(bus.peek(0x4000).unwrap(), 0xaa);
+        assert_eq!(bus.peek_bank(Region::Rom, 1, 0x0000).unwrap(), 0xaa);
+        bus.poke_bank(Region::ExternalRam, 0, 0x0010, 0x55).unwrap();
+        assert_eq!(bus.peek(0xa010).unwrap(), 0x55);
+        assert!(bus.peek_bank(Region::Rom, 2, 0x0000).is_err());
+        assert!(bus.peek_bank(Region::Rom, 0, 0x4000).is_err());
+
+        // vram bank 1 is not visible until selected
+        bus.poke_bank(Region::Vram, 1, 0x0000, 0x77).unwrap();
+        assert_eq!(bus.peek(0x8000).unwrap(), 0x00);
+        bus.poke(VRAM_BANK_ADDR, 0x01).unwrap();
+        assert_eq!(bus.peek(0x8000).unwrap(), 0x77);
+        assert_eq!(bus.take_pause(), None);
+
+        // timer and lcd registers change without the side effects of a write
+        bus.poke(TIMA_ADDR, 0x30).unwrap();
+        bus.poke(TAC_ADDR, 0b101).unwrap();
+        bus.tick(8);
+        bus.poke(DIV_ADDR, 0x42).unwrap();
+        assert_eq!((bus.peek(DIV_ADDR).unwrap(), bus.peek(TIMA_ADDR).unwrap()), (0x42, 0x30));
+        bus.poke(LCDC_ADDR, 0x80).unwrap();
+        bus.poke(STAT_ADDR, 0x08).unwrap();
+        assert_eq!(bus.peek(INTERRUPT_FLAG_REG_ADDR as u16).unwrap() & STAT_INTERRUPT, 0);
+    }
+
+    #[test]
+    fn test_bus_access_lock() {
+        test_bus!(bus);
+        bus.write(0x8000, 0x11).unwrap();
+        bus.write(0xfe00, 0x22).unwrap();
+
+        bus.set_lcd_mode(LcdMode::OamScan);
+        assert_eq!(bus.read(0x8000).unwrap(), 0x11);
+        assert_eq!(bus.read(0xfe00).unwrap(), 0xff);
+        bus.write(0xfe00, 0x33).unwrap();
+
+        bus.set_lcd_mode(LcdMode::Drawing);
+        assert_eq!(bus.read(0x8000).unwrap(), 0xff);
+        bus.write(0x8000, 0x44).unwrap();
+        // tools still see the memory
+        assert_eq!(bus.peek(0x8000).unwrap(), 0x11);
+        assert_eq!(bus.peek(0xfe00).unwrap(), 0x22);
+
+        bus.set_access_lock(false);
+        assert_eq!(bus.read(0x8000).unwrap(), 0x11);
+        assert_eq!(bus.read(0xfe00).unwrap(), 0x22);
+    }
+
+    #[test]
+    fn test_bus_timer() {
+        test_bus!(bus);
+        assert_eq!(bus.scheduler().deadline(Event::Timer), None);
+        bus.tick(0x100);
+        assert_eq!(bus.read(DIV_ADDR).unwrap(), 0x01);
+
+        bus.write(TIMA_ADDR, 0xfe).unwrap();
+        bus.write(TAC_ADDR, 0b101).unwrap();
+        assert_eq!(bus.scheduler().cycles_until_next(), Some(36));
+        bus.tick(24);
+        assert_eq!(bus.read(TIMA_ADDR).unwrap(), 0xff);
+        bus.tick(8);
+        assert_eq!(bus.read(TIMA_ADDR).unwrap(), 0x00);
+        assert_eq!(bus.read(INTERRUPT_FLAG_REG_ADDR as u16).unwrap() & TIMER_INTERRUPT, 0);
+        bus.tick(4);
+        assert_ne!(bus.read(INTERRUPT_FLAG_REG_ADDR as u16).unwrap() & TIMER_INTERRUPT, 0);
+        assert_eq!(bus.scheduler().cycles_until_next(), Some(256 * 16));
+    }
+
+    #[test]
+    fn test_bus_ppu() {
+        test_bus!(bus);
+        bus.write(LCDC_ADDR, 0x80).unwrap();
+        assert_eq!(bus.scheduler().deadline(Event::Ppu), Some(80));
+        // instructions end past the events, the ppu catches up to the end of each
+        for _ in 0..144 * 456 / 12 - 1 {
+            bus.tick(12);
         }
+        assert_eq!(bus.read(LY_ADDR).unwrap(), 143);
+        assert_eq!(bus.read(INTERRUPT_FLAG_REG_ADDR as u16).unwrap() & VBLANK_INTERRUPT, 0);
+        bus.tick(12);
+        assert_eq!((bus.read(LY_ADDR).unwrap(), bus.lcd_mode()), (144, LcdMode::VBlank));
+        assert_ne!(bus.read(INTERRUPT_FLAG_REG_ADDR as u16).unwrap() & VBLANK_INTERRUPT, 0);
+        // vram is free again
+        bus.write(0x8000, 0x42).unwrap();
+        assert_eq!(bus.read(0x8000).unwrap(), 0x42);
     }
 }
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute, // opcode fetch
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub val: u8,
+}
+
+// returned by a hook callback to let the emulation go on or to request a pause
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    Continue,
+    Pause,
+}
+
+pub type HookFn = Box<dyn FnMut(&Access) -> HookAction>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(usize);
+
+struct Hook {
+    id: HookId,
+    kind: AccessKind,
+    range: RangeInclusive<u16>,
+    val: Option<u8>, // fire only when the accessed value matches
+    f: HookFn,
+}
+
+impl Hook {
+    fn matches(&self, access: &Access) -> bool {
+        self.kind == access.kind
+            && self.range.contains(&access.addr)
+            && self.val.is_none_or(|v| v == access.val)
+    }
+}
+
+pub struct Hooks {
+    inner: Vec<Hook>,
+    next_id: usize,
+    pause: Option<Access>,
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks {
+            inner: Vec::new(),
+            next_id: 0usize,
+            pause: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn add(&mut self, kind: AccessKind, range: RangeInclusive<u16>, val: Option<u8>, f: HookFn) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        self.inner.push(Hook { id, kind, range, val, f });
+        id
+    }
+
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let len = self.inner.len();
+        self.inner.retain(|h| h.id != id);
+        self.inner.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+
+    // every matching hook is called, the first pause request is kept until it is taken
+    pub fn dispatch(&mut self, access: Access) {
+        for hook in self.inner.iter_mut().filter(|h| h.matches(&access)) {
+            if (hook.f)(&access) == HookAction::Pause && self.pause.is_none() {
+                self.pause = Some(access);
+            }
+        }
+    }
+
+    pub fn take_pause(&mut self) -> Option<Access> {
+        self.pause.take()
+    }
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks::new()
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("len", &self.inner.len())
+            .field("pause", &self.pause)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_hooks_dispatch() {
+        let mut hooks = Hooks::new();
+        assert!(hooks.is_empty());
+        let count = Rc::new(Cell::new(0));
+        let c = count.clone();
+        let id = hooks.add(AccessKind::Write, 0xc000..=0xc0ff, None, Box::new(move |_| {
+            c.set(c.get() + 1);
+            HookAction::Continue
+        }));
+        hooks.add(AccessKind::Write, 0xc000..=0xc0ff, Some(0x42), Box::new(|_| HookAction::Pause));
+
+        hooks.dispatch(Access { kind: AccessKind::Write, addr: 0xc010, val: 0x01 });
+        hooks.dispatch(Access { kind: AccessKind::Read, addr: 0xc010, val: 0x42 });
+        hooks.dispatch(Access { kind: AccessKind::Write, addr: 0xc100, val: 0x42 });
+        assert_eq!(count.get(), 1);
+        assert_eq!(hooks.take_pause(), None);
+
+        let access = Access { kind: AccessKind::Write, addr: 0xc0ff, val: 0x42 };
+        hooks.dispatch(access);
+        assert_eq!(count.get(), 2);
+        assert_eq!(hooks.take_pause(), Some(access));
+        assert_eq!(hooks.take_pause(), None);
+
+        assert!(hooks.remove(id));
+        assert!(!hooks.remove(id));
+        hooks.dispatch(access);
+        assert_eq!(count.get(), 2);
+    }
+}
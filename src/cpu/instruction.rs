@@ -1,6 +1,8 @@
 use crate::error::*;
 use super::register::Register;
 use super::bus::Bus;
+use super::oam_bug::OamBug;
+use crate::util;
 
 // instruction operation fn(instruction opcode, register, bus) -> consumed clock cycle
 pub type InstructionFn = fn(inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize>;
@@ -238,16 +240,16 @@ impl Instruction {
             Instruction::LD_IO_N_A => Ok(dummy), // 0xe0 nn
             Instruction::LD_A_IO_C => Ok(dummy), // 0xf2
             Instruction::LD_IO_C_A => Ok(dummy), // 0xe2
-            Instruction::LDI_HL_A => Ok(dummy), // 0x22
-            Instruction::LDI_A_HL => Ok(dummy), // 0x2a
-            Instruction::LDD_HL_A => Ok(dummy), // 0x32
-            Instruction::LDD_A_HL => Ok(dummy), //0x3a
+            Instruction::LDI_HL_A => Ok(ldi_hl_a), // 0x22
+            Instruction::LDI_A_HL => Ok(ldi_a_hl), // 0x2a
+            Instruction::LDD_HL_A => Ok(ldd_hl_a), // 0x32
+            Instruction::LDD_A_HL => Ok(ldd_a_hl), //0x3a
             // 16bit load
             Instruction::LD_RR_NN => Ok(dummy), // 0xx1 nn nn
             Instruction::LD_SP_HL => Ok(dummy), // 0xf9
             Instruction::LD_NN_SP => Ok(dummy), // 0x08
-            Instruction::PUSH_RR => Ok(dummy), // 0xx5
-            Instruction::POP_RR => Ok(dummy), // 0xx1
+            Instruction::PUSH_RR => Ok(push_rr), // 0xx5
+            Instruction::POP_RR => Ok(pop_rr), // 0xx1
             // 8bit arithmethic/logic
             Instruction::ADD_A_R => Ok(dummy), // 0x8x
             Instruction::ADD_A_N => Ok(dummy), // 0xc6 nn
@@ -281,8 +283,8 @@ impl Instruction {
             Instruction::CPL => Ok(dummy), // 0x2f
             // 16bit arithmethic/logic
             Instruction::ADD_HL_RR => Ok(dummy), // 0xx9
-            Instruction::INC_RR => Ok(dummy), // 0xx3
-            Instruction::DEC_RR => Ok(dummy), // 0xxb
+            Instruction::INC_RR => Ok(inc_rr), // 0xx3
+            Instruction::DEC_RR => Ok(dec_rr), // 0xxb
             Instruction::ADD_SP_DD => Ok(dummy), // 0xe8
             Instruction::LD_HL_SP_DD => Ok(dummy), // 0xf8
             Instruction::RLCA => Ok(dummy), // 0x07
@@ -345,6 +347,10 @@ fn op2(inst: u8) -> usize {
     (inst & 0b0011_1000) as usize
 }
 
+fn op_rr(inst: u8) -> usize {
+    ((inst & 0b0011_0000) >> 4) as usize
+}
+
 
 pub fn dummy(inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
     Ok(0usize)
@@ -356,15 +362,89 @@ pub fn ld_r_r(inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
     Ok(0usize)
 }
 
+pub fn ldi_hl_a(_inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
+    let hl = reg.hl();
+    bus.write(hl, reg.a())?;
+    bus.trigger_oam_bug(hl, OamBug::Write);
+    reg.set_hl(hl.wrapping_add(1));
+    Ok(8usize)
+}
+
+pub fn ldi_a_hl(_inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
+    let hl = reg.hl();
+    reg.set_a(bus.read(hl)?);
+    bus.trigger_oam_bug(hl, OamBug::ReadIncrease);
+    reg.set_hl(hl.wrapping_add(1));
+    Ok(8usize)
+}
+
+pub fn ldd_hl_a(_inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
+    let hl = reg.hl();
+    bus.write(hl, reg.a())?;
+    bus.trigger_oam_bug(hl, OamBug::Write);
+    reg.set_hl(hl.wrapping_sub(1));
+    Ok(8usize)
+}
+
+pub fn ldd_a_hl(_inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
+    let hl = reg.hl();
+    reg.set_a(bus.read(hl)?);
+    bus.trigger_oam_bug(hl, OamBug::ReadIncrease);
+    reg.set_hl(hl.wrapping_sub(1));
+    Ok(8usize)
+}
+
+// push and pop trigger the oam bug in every cycle sp is on the bus
+pub fn push_rr(inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
+    let (hi, lo) = util::split_u16(reg.get_r16_stack(op_rr(inst))?);
+    let mut sp = reg.sp();
+    bus.trigger_oam_bug(sp, OamBug::Write);
+    sp = sp.wrapping_sub(1);
+    bus.write(sp, hi)?;
+    bus.trigger_oam_bug(sp, OamBug::Write);
+    sp = sp.wrapping_sub(1);
+    bus.write(sp, lo)?;
+    bus.trigger_oam_bug(sp, OamBug::Write);
+    reg.set_sp(sp);
+    Ok(16usize)
+}
+
+pub fn pop_rr(inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
+    let mut sp = reg.sp();
+    let lo = bus.read(sp)?;
+    bus.trigger_oam_bug(sp, OamBug::ReadIncrease);
+    sp = sp.wrapping_add(1);
+    let hi = bus.read(sp)?;
+    bus.trigger_oam_bug(sp, OamBug::ReadIncrease);
+    sp = sp.wrapping_add(1);
+    reg.set_sp(sp);
+    reg.set_r16_stack(op_rr(inst), util::u8_to_u16(hi, lo))?;
+    Ok(12usize)
+}
+
+// the value before inc/dec is on the address bus
+pub fn inc_rr(inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
+    let index = op_rr(inst);
+    let val = reg.get_r16(index)?;
+    bus.trigger_oam_bug(val, OamBug::Write);
+    reg.set_r16(index, val.wrapping_add(1))?;
+    Ok(8usize)
+}
+
+pub fn dec_rr(inst: u8, reg: &mut Register, bus: &mut Bus) -> GBResult<usize> {
+    let index = op_rr(inst);
+    let val = reg.get_r16(index)?;
+    bus.trigger_oam_bug(val, OamBug::Write);
+    reg.set_r16(index, val.wrapping_sub(1))?;
+    Ok(8usize)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Instruction;
+    use super::*;
+    use crate::ppu::{LcdMode, LCDC_ADDR, LINE_DOTS, OAM_SCAN_DOTS};
+    use crate::cpu::oam_bug::OAM_ROWS;
     use crate::cpu::register::Register;
-    use crate::cpu::bus::Bus;
-    use crate::mem::ram::Ram;
-    use crate::mem::rom::Rom;
-    use crate::cartridge::Cartridge;
-    use crate::timer::Timer;
     #[test]
     fn test_instruction_from() {
         assert_eq!(Instruction::from(0x20).unwrap(), Instruction::JR_F_PC_DD);
@@ -401,16 +481,69 @@ mod tests {
     #[test]
     fn test_instruction_function() {
         let mut reg = Register::new();
-        let mut ram = Ram::new(Vec::new());
-        let mut hram = Ram::new(Vec::new());
-        let cart_rom = Rom::new(Vec::new());
-        let mut cart_ram = Ram::new(Vec::new());
-        let mut cart = Cartridge::new(&cart_rom, &mut cart_ram, false, false);
-        let mut timer = Timer::new();
-        let mut bus = Bus::new(&mut ram, &mut hram, &mut cart, &mut timer);
+        test_bus!(bus);
         let inst = Instruction::NOP;
         let func = inst.function().unwrap();
         let res = func(0u8, &mut reg, &mut bus).is_ok();
         assert_eq!(true, true)
     }
+    #[test]
+    fn test_inc_rr_push_pop() {
+        let mut reg = Register::new();
+        test_bus!(bus);
+
+        reg.set_bc(0xffff);
+        assert_eq!(Instruction::from(0x03).unwrap().function().unwrap()(0x03, &mut reg, &mut bus).unwrap(), 8);
+        assert_eq!(reg.bc(), 0x0000);
+        reg.set_sp(0xfffe);
+        reg.set_de(0x1234);
+        Instruction::PUSH_RR.function().unwrap()(0xd5, &mut reg, &mut bus).unwrap();
+        assert_eq!(reg.sp(), 0xfffc);
+        Instruction::POP_RR.function().unwrap()(0xf1, &mut reg, &mut bus).unwrap();
+        assert_eq!(reg.sp(), 0xfffe);
+        assert_eq!(reg.af(), 0x1230);
+    }
+
+    #[test]
+    fn test_oam_bug_inc_rr() {
+        let mut reg = Register::new();
+        test_bus!(bus);
+        for i in 0..0xa0u16 {
+            bus.poke(0xfe00 + i, i as u8).unwrap();
+        }
+        bus.set_lcd_mode(LcdMode::OamScan);
+        bus.set_oam_scan_row(1);
+
+        reg.set_hl(0xfe10);
+        inc_rr(0x23, &mut reg, &mut bus).unwrap();
+        assert_eq!(reg.hl(), 0xfe11);
+        assert_eq!(bus.peek(0xfe0a).unwrap(), 0x02);
+
+        bus.set_oam_bug(false);
+        bus.poke(0xfe0a, 0x0a).unwrap();
+        dec_rr(0x2b, &mut reg, &mut bus).unwrap();
+        assert_eq!(bus.peek(0xfe0a).unwrap(), 0x0a);
+    }
+
+    #[test]
+    fn test_oam_bug_lcd() {
+        let mut reg = Register::new();
+        test_bus!(bus);
+        for i in 0..0xa0u16 {
+            bus.poke(0xfe00 + i, i as u8).unwrap();
+        }
+        // the first line after the lcd starts has no oam scan, halfway through the next one
+        // the ppu reads the middle row. the ticks leave the ppu behind until it is synced
+        bus.write(LCDC_ADDR, 0x80).unwrap();
+        bus.tick(LINE_DOTS as usize);
+        bus.tick(OAM_SCAN_DOTS as usize / 2);
+        assert_eq!(bus.lcd_mode(), LcdMode::OamScan);
+
+        // a write corruption copies the last 3 words of the row before
+        let row = OAM_ROWS / 2;
+        reg.set_hl(0xfe10);
+        inc_rr(0x23, &mut reg, &mut bus).unwrap();
+        assert_eq!(bus.peek(0xfe00 + row as u16 * 8 + 2).unwrap(), ((row - 1) * 8 + 2) as u8);
+        assert_eq!(bus.peek(0xfe0a).unwrap(), 0x0a);
+    }
 }
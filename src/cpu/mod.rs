@@ -1,5 +1,24 @@
-mod register;
-mod bus;
+// a bus over a cartridge of the rom image for tests, the parts it borrows live in the scope of the caller
+#[cfg(test)]
+macro_rules! test_bus {
+    ($bus:ident) => {
+        test_bus!($bus, crate::cartridge::header::test_rom(0x00, 0x00, 0x00))
+    };
+    ($bus:ident, $rom:expr) => {
+        let mut ram = crate::mem::ram::Ram::new(vec![0u8; 0x2000]);
+        let mut hram = crate::mem::ram::Ram::new(vec![0u8; 0x7f]);
+        let mut cart_rom = crate::mem::rom::Rom::new($rom);
+        let mut cart_ram = crate::mem::ram::Ram::new(Vec::new());
+        let mut cart = crate::cartridge::Cartridge::new(&mut cart_rom, &mut cart_ram).unwrap();
+        let mut timer = crate::timer::Timer::new();
+        let mut $bus = crate::cpu::bus::Bus::new(&mut ram, &mut hram, &mut cart, &mut timer);
+    };
+}
+
+pub mod register;
+pub mod bus;
+pub mod hook;
+pub mod oam_bug;
 mod instruction;
 
 use register::*;
@@ -24,26 +43,90 @@ impl<'a> Cpu<'a> {
         }
     }
 
-    pub fn step(&self) -> GBResult<()> {
+    pub fn step(&mut self) -> GBResult<()> {
+        let consumed_cycle = self.execute()?;
+        self.bus.tick(consumed_cycle);
+        Ok(())
+    }
+
+    // runs instructions for at least the cycles, e.g. a frame, and returns the cycles run.
+    // between the deadlines of the scheduler the instructions only advance the clock,
+    // the deadline is checked after each one as a write can move it
+    pub fn run(&mut self, cycles: usize) -> GBResult<usize> {
+        let start = self.cycle;
+        while self.cycle - start < cycles {
+            let consumed_cycle = self.execute()?;
+            self.bus.advance(consumed_cycle);
+            if self.bus.event_due() {
+                self.bus.handle_events();
+            }
+        }
+        self.bus.sync();
+        Ok(self.cycle - start)
+    }
+
+    fn execute(&mut self) -> GBResult<usize> {
         let inst = self.fetch()?;
         let f = self.decode(inst)?;
-        let consumed_cycle = self.exec(f);
-        Ok(())
+        let consumed_cycle = self.exec(inst, f)?;
+        self.cycle += consumed_cycle;
+        Ok(consumed_cycle)
+    }
+
+    pub fn bus(&mut self) -> &mut Bus<'a> {
+        self.bus
     }
 
-    fn fetch(&self) -> GBResult<u8> {
-        Ok(0u8)
+    fn fetch(&mut self) -> GBResult<u8> {
+        let pc = self.register.pc();
+        let inst = self.bus.fetch(pc)?;
+        self.register.set_pc(pc.wrapping_add(1));
+        Ok(inst)
     }
 
     fn decode(&self, inst: u8) -> GBResult<InstructionFn> {
-        Ok(dummy)
+        Instruction::from(inst)?.function()
     }
     
-    fn exec(&self, f: InstructionFn) -> GBResult<usize> {
-        Ok(0usize)
+    fn exec(&mut self, inst: u8, f: InstructionFn) -> GBResult<usize> {
+        f(inst, self.register, self.bus)
     }
 }
 
 enum Ime {
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::mem::*;
+    use crate::timer::*;
+
+    #[test]
+    fn test_cpu_run() {
+        // ldi (hl),a after the header, with an rtc to see the cartridge catch up
+        let mut v = test_rom(0x10, 0x00, 0x02);
+        v[0x0150..ROM_BANK_ADDR_TOP].fill(0x22);
+        test_bus!(bus, v);
+        bus.write(TIMA_ADDR, 0xff).unwrap();
+        bus.write(TAC_ADDR, 0b101).unwrap();
+        let mut reg = Register::new();
+        reg.set_pc(0x0150);
+        reg.set_hl(0xc000);
+        let mut cpu = Cpu::new(&mut reg, &mut bus, false);
+
+        assert_eq!(cpu.run(1000).unwrap(), 1000);
+        let bus = cpu.bus();
+        assert_eq!(bus.scheduler().now(), 1000);
+        assert_ne!(bus.read(INTERRUPT_FLAG_REG_ADDR as u16).unwrap() & TIMER_INTERRUPT, 0);
+        // the rtc runs with the cycles although the cpu did not access it
+        bus.tick(crate::cartridge::mbc3::CPU_CLOCK_HZ as usize * 2);
+        bus.write(0x0000, 0x0a).unwrap();
+        bus.write(0x4000, 0x08).unwrap();
+        bus.write(0x6000, 0x00).unwrap();
+        bus.write(0x6000, 0x01).unwrap();
+        assert_eq!(bus.read(0xa000).unwrap(), 2);
+    }
+}
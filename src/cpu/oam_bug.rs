@@ -0,0 +1,105 @@
+use crate::mem::ram::Ram;
+
+// DMG corrupts oam when the cpu puts an address of 0xfe00-0xfeff on the bus during oam scan.
+// oam is seen as 20 rows of 4 words and the row the ppu is reading gets overwritten.
+pub const OAM_ROWS: usize = 20;
+const ROW_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OamBug {
+    Write,
+    Read,
+    ReadIncrease, // read and inc/dec of the same register in one cycle
+}
+
+fn word(oam: &Ram, row: usize, i: usize) -> u16 {
+    let offset = row * ROW_SIZE + i * 2;
+    u16::from_le_bytes([oam.get(offset).unwrap_or(0), oam.get(offset + 1).unwrap_or(0)])
+}
+
+fn set_word(oam: &mut Ram, row: usize, i: usize, val: u16) {
+    let offset = row * ROW_SIZE + i * 2;
+    let [lo, hi] = val.to_le_bytes();
+    oam.set(offset, lo);
+    oam.set(offset + 1, hi);
+}
+
+fn copy_row(oam: &mut Ram, from: usize, to: usize, top: usize) {
+    for i in top..4 {
+        let w = word(oam, from, i);
+        set_word(oam, to, i, w);
+    }
+}
+
+pub fn corrupt(oam: &mut Ram, row: usize, kind: OamBug) {
+    // the first row is never corrupted
+    if row == 0 || row >= OAM_ROWS {
+        return;
+    }
+    match kind {
+        OamBug::Write => {
+            let a = word(oam, row, 0);
+            let b = word(oam, row - 1, 0);
+            let c = word(oam, row - 1, 2);
+            set_word(oam, row, 0, ((a ^ c) & (b ^ c)) ^ c);
+            copy_row(oam, row - 1, row, 1);
+        },
+        OamBug::Read => {
+            let a = word(oam, row, 0);
+            let b = word(oam, row - 1, 0);
+            let c = word(oam, row - 1, 2);
+            set_word(oam, row, 0, b | (a & c));
+            copy_row(oam, row - 1, row, 1);
+        },
+        OamBug::ReadIncrease => {
+            // not applied to the first four rows nor the last one
+            if (4..OAM_ROWS - 1).contains(&row) {
+                let a = word(oam, row - 2, 0);
+                let b = word(oam, row - 1, 0);
+                let c = word(oam, row, 0);
+                let d = word(oam, row - 1, 2);
+                set_word(oam, row - 1, 0, (b & (a | c | d)) | (a & c & d));
+                copy_row(oam, row - 1, row, 0);
+                copy_row(oam, row - 1, row - 2, 0);
+            }
+            corrupt(oam, row, OamBug::Read);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oam() -> Ram {
+        Ram::new((0..0xa0).map(|i| i as u8).collect())
+    }
+
+    #[test]
+    fn test_corrupt_write() {
+        let mut oam = oam();
+        corrupt(&mut oam, 0, OamBug::Write);
+        assert_eq!(word(&oam, 0, 0), 0x0100);
+
+        corrupt(&mut oam, 1, OamBug::Write);
+        // a = 0x0908, b = 0x0100, c = 0x0504
+        assert_eq!(word(&oam, 1, 0), ((0x0908 ^ 0x0504) & (0x0100 ^ 0x0504)) ^ 0x0504);
+        assert_eq!(word(&oam, 1, 1), word(&oam, 0, 1));
+        assert_eq!(word(&oam, 1, 3), word(&oam, 0, 3));
+        assert_eq!(word(&oam, 2, 0), 0x1110);
+    }
+
+    #[test]
+    fn test_corrupt_read_increase() {
+        let mut oam = oam();
+        corrupt(&mut oam, 5, OamBug::ReadIncrease);
+        // a = 0x1918, b = 0x2120, c = 0x2928, d = 0x2524
+        let b = (0x2120 & (0x1918 | 0x2928 | 0x2524)) | (0x1918 & 0x2928 & 0x2524);
+        assert_eq!(word(&oam, 4, 0), b);
+        assert_eq!(word(&oam, 3, 0), b);
+        assert_eq!(word(&oam, 3, 2), word(&oam, 4, 2));
+        // the read corruption is applied on top
+        assert_eq!(word(&oam, 5, 0), b | (b & word(&oam, 4, 2)));
+        assert_eq!(word(&oam, 5, 3), word(&oam, 4, 3));
+    }
+}
@@ -15,6 +15,7 @@ pub const REG_BC: usize = 0;
 pub const REG_DE: usize = 1;
 pub const REG_HL: usize = 2;
 pub const REG_SP: usize = 3;
+pub const REG_AF: usize = 3; // push/pop
 
 #[derive(Debug)]
 pub struct Register {
@@ -190,6 +191,33 @@ impl Register {
         }
     }
 
+    pub fn set_r16(&mut self, index: usize, val: u16) -> GBResult<()> {
+        match index {
+            REG_BC => self.set_bc(val),
+            REG_DE => self.set_de(val),
+            REG_HL => self.set_hl(val),
+            REG_SP => self.set_sp(val),
+            _ => return Err(GBError::InvalidInput),
+        }
+        Ok(())
+    }
+
+    pub fn get_r16_stack(&self, index: usize) -> GBResult<u16> {
+        match index {
+            REG_AF => Ok(self.af()),
+            _ => self.get_r16(index),
+        }
+    }
+
+    pub fn set_r16_stack(&mut self, index: usize, val: u16) -> GBResult<()> {
+        match index {
+            // lower 4 bits of f are always 0
+            REG_AF => self.set_af(val & 0xfff0),
+            _ => return self.set_r16(index, val),
+        }
+        Ok(())
+    }
+
     pub fn set_r8(&mut self, index: usize, val: u8) -> GBResult<()> {
         match index {
             REG_B => self.set_b(val),
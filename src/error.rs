@@ -5,8 +5,22 @@ pub enum GBError {
     InvalidData,
     InvalidInput,
     NotFound,
+    // memory
+    InvalidAddress(u16),
+    InvalidBank(u16),
+    // cartridge
+    HeaderTooShort(usize),
+    UnsupportedCartridgeType(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    HeaderChecksumMismatch(u8, u8),
+    GlobalChecksumMismatch(u16, u16),
+    NotRom(String),
+    InvalidPatch(String),
     // cpu
     InstructionNotFound(u8),
+    // file
+    Io(std::io::ErrorKind),
 }
 
 impl std::error::Error for GBError {}
@@ -18,9 +32,26 @@ impl fmt::Display for GBError {
             InvalidData => write!(f, "Invalid Data."),
             InvalidInput => write!(f, "Invalid Input."),
             NotFound => write!(f, "Not Found."),
+            InvalidAddress(addr) => write!(f, "Invalid address(0x{:04x}).", addr),
+            InvalidBank(bank) => write!(f, "Invalid bank({}).", bank),
+            HeaderTooShort(len) => write!(f, "Cartridge header too short({} bytes).", len),
+            UnsupportedCartridgeType(code) => write!(f, "Unsupported cartridge type(0x{:02x}).", code),
+            InvalidRomSize(code) => write!(f, "Invalid rom size(0x{:02x}).", code),
+            InvalidRamSize(code) => write!(f, "Invalid ram size(0x{:02x}).", code),
+            HeaderChecksumMismatch(want, got) => write!(f, "Header checksum mismatch(header: 0x{:02x}, computed: 0x{:02x}).", want, got),
+            GlobalChecksumMismatch(want, got) => write!(f, "Global checksum mismatch(header: 0x{:04x}, computed: 0x{:04x}).", want, got),
+            NotRom(reason) => write!(f, "Not a rom({}).", reason),
+            InvalidPatch(reason) => write!(f, "Invalid patch({}).", reason),
             InstructionNotFound(inst) => write!(f, "Instruction not found({}).", inst),
+            Io(kind) => write!(f, "I/O error({:?}).", kind),
         }
     }
 }
 
+impl From<std::io::Error> for GBError {
+    fn from(e: std::io::Error) -> Self {
+        GBError::Io(e.kind())
+    }
+}
+
 pub type GBResult<T> = Result<T, GBError>;
@@ -1,9 +1,13 @@
 mod util;
-mod error;
-mod cpu;
-mod mem;
-mod cartridge;
-mod timer;
+pub mod error;
+pub mod device;
+pub mod cpu;
+pub mod mem;
+pub mod cartridge;
+pub mod timer;
+pub mod scheduler;
+pub mod ppu;
+pub mod wasm;
 
 use wasm_bindgen::prelude::*;
 
@@ -0,0 +1,203 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+
+use crate::cartridge::header::*;
+use crate::cartridge::unlicensed::Sachen;
+use crate::error::*;
+use super::patch::{self, PATCH_EXTENSIONS};
+use super::rom::Rom;
+
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ROM_EXTENSIONS: [&str; 3] = ["gb", "gbc", "sgb"];
+const MIN_ROM_SIZE: usize = 0x8000;
+// sachen cartridges keep the logo the boot rom checks at 0x0184, scrambled
+const SACHEN_LOGO_ADDR: usize = 0x0184;
+
+// applies a patch next to the rom with the same name, e.g. game.ips for game.gb
+pub fn load_file(path: &Path) -> GBResult<Rom> {
+    load_file_with_patch(path, patch_path(path).as_deref())
+}
+
+pub fn load_file_with_patch(path: &Path, patch: Option<&Path>) -> GBResult<Rom> {
+    let patch = match patch {
+        Some(patch) => Some(fs::read(patch)?),
+        None => None,
+    };
+    load_patched(&fs::read(path)?, patch.as_deref())
+}
+
+pub fn patch_path(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS.iter().map(|ext| rom_path.with_extension(ext)).find(|path| path.is_file())
+}
+
+pub fn load(data: &[u8]) -> GBResult<Rom> {
+    load_patched(data, None)
+}
+
+// data is a raw rom, a zip archive or a gzip file, told apart by their magic numbers.
+// the patch is applied to the rom as dumped, before it is normalized
+pub fn load_patched(data: &[u8], patch: Option<&[u8]>) -> GBResult<Rom> {
+    let v = if data.starts_with(&ZIP_MAGIC) {
+        unzip(data)?
+    } else if data.starts_with(&GZIP_MAGIC) {
+        let mut v = vec![];
+        GzDecoder::new(data).read_to_end(&mut v).map_err(|e| GBError::NotRom(format!("broken gzip file: {}", e)))?;
+        v
+    } else {
+        data.to_vec()
+    };
+    let v = match patch {
+        Some(patch) => patch::apply(&v, patch)?,
+        None => v,
+    };
+    validate(&v)?;
+    Ok(Rom::new(normalize(v)))
+}
+
+// the first entry with a rom extension
+fn unzip(data: &[u8]) -> GBResult<Vec<u8>> {
+    let broken = |e: zip::result::ZipError| GBError::NotRom(format!("broken zip archive: {}", e));
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(broken)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(broken)?;
+        let is_rom = Path::new(file.name())
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ROM_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+        if file.is_file() && is_rom {
+            let mut v = vec![];
+            file.read_to_end(&mut v).map_err(|e| GBError::NotRom(format!("broken zip archive: {}", e)))?;
+            return Ok(v);
+        }
+    }
+    Err(GBError::NotRom("no .gb or .gbc file in the zip archive".to_string()))
+}
+
+// every cartridge which boots has the logo, mmm01 collections only in the header of the menu
+fn validate(v: &[u8]) -> GBResult<()> {
+    if v.len() <= HEADER_TAIL {
+        return Err(GBError::NotRom(format!("{} bytes is too short for a header", v.len())));
+    }
+    let logo = |top: usize| v.get(top..top + NINTENDO_LOGO.len()) == Some(&NINTENDO_LOGO[..]);
+    let sachen = (0..NINTENDO_LOGO.len()).all(|i| v.get(Sachen::header_addr(SACHEN_LOGO_ADDR + i)) == Some(&NINTENDO_LOGO[i]));
+    let menu = v.len().saturating_sub(MIN_ROM_SIZE) + LOGO_ADDR;
+    if logo(LOGO_ADDR) || sachen || logo(menu) {
+        return Ok(());
+    }
+    Err(GBError::NotRom("no Nintendo logo in the header".to_string()))
+}
+
+// overdumps of an odd size are cut to the size in the header, undersized roms are padded to 32KB
+// and the rest is mirrored up to a power of two like a missing address line does.
+// collections and unlicensed cartridges often understate their size, so a power of two is kept
+pub fn normalize(mut v: Vec<u8>) -> Vec<u8> {
+    let declared = match v.get(ROM_SIZE_ADDR) {
+        Some(n @ 0x00..=0x08) => Some(MIN_ROM_SIZE << n),
+        _ => None,
+    };
+    if let Some(size) = declared {
+        if !v.len().is_power_of_two() {
+            v.truncate(size);
+        }
+    }
+    if v.len() < MIN_ROM_SIZE {
+        v.resize(MIN_ROM_SIZE, 0xff);
+    }
+    let len = v.len();
+    if len.is_power_of_two() {
+        return v;
+    }
+    let top = 1 << (usize::BITS - 1 - len.leading_zeros());
+    let rest = len - top;
+    v.extend((len..len.next_power_of_two()).map(|i| v[top + (i - top) % rest]).collect::<Vec<u8>>());
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn rom() -> Vec<u8> {
+        let mut v = test_rom(0x00, 0x01, 0x00);
+        v[LOGO_ADDR..LOGO_ADDR + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        v[0x8000] = 0x42;
+        v
+    }
+
+    #[test]
+    fn test_load_archives() {
+        assert_eq!(load(&rom()).unwrap().get(0x8000), Some(0x42));
+
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(&rom()).unwrap();
+        assert_eq!(load(&gz.finish().unwrap()).unwrap().get(0x8000), Some(0x42));
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        let options = zip::write::FileOptions::default();
+        zip.start_file("readme.txt", options).unwrap();
+        zip.write_all(b"not a rom").unwrap();
+        zip.start_file("Game.GBC", options).unwrap();
+        zip.write_all(&rom()).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        assert_eq!(load(&data).unwrap().len(), 0x10000);
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("readme.txt", options).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        assert!(matches!(load(&data), Err(GBError::NotRom(_))));
+        assert!(matches!(load(b"hello"), Err(GBError::NotRom(_))));
+        assert!(matches!(load(&test_rom(0x00, 0x00, 0x00)), Err(GBError::NotRom(_))));
+    }
+
+    #[test]
+    fn test_load_patched() {
+        let dir = std::env::temp_dir().join(format!("rusgb-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.gb");
+        fs::write(&path, rom()).unwrap();
+        assert_eq!(load_file(&path).unwrap().get(0x8000), Some(0x42));
+
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0x00, 0x80, 0x00, 0x00, 0x01, 0x24]);
+        ips.extend_from_slice(b"EOF");
+        assert_eq!(load_patched(&rom(), Some(&ips)).unwrap().get(0x8000), Some(0x24));
+        fs::write(dir.join("game.ips"), &ips).unwrap();
+        assert_eq!(patch_path(&path), Some(dir.join("game.ips")));
+        assert_eq!(load_file(&path).unwrap().get(0x8000), Some(0x24));
+
+        let other = dir.join("other.ips");
+        fs::write(&other, b"BROKEN").unwrap();
+        assert!(matches!(load_file_with_patch(&path, Some(&other)), Err(GBError::InvalidPatch(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_normalize() {
+        // overdump
+        let mut v = rom();
+        v.extend(vec![0u8; 0x1000]);
+        assert_eq!(normalize(v).len(), 0x10000);
+
+        // undersized homebrew with a broken size
+        let mut v = rom();
+        v[ROM_SIZE_ADDR] = 0xff;
+        v.truncate(0x4000);
+        let v = normalize(v);
+        assert_eq!((v.len(), v[0x7fff]), (0x8000, 0xff));
+
+        // 48KB mirrors its last 16KB
+        let mut v = rom();
+        v[ROM_SIZE_ADDR] = 0x52;
+        v.truncate(0xc000);
+        v[0xbfff] = 0x11;
+        let v = normalize(v);
+        assert_eq!((v.len(), v[0xc000], v[0xffff]), (0x10000, 0x42, 0x11));
+    }
+}
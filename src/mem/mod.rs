@@ -1,5 +1,7 @@
 pub mod rom;
 pub mod ram;
+pub mod loader;
+pub mod patch;
 
 pub const ROM_ADDR_TOP: usize = 0x0000;
 pub const ROM_ADDR_TAIL: usize = 0x3fff;
@@ -7,6 +9,8 @@ pub const ROM_BANK_ADDR_TOP: usize = 0x4000;
 pub const ROM_BANK_ADDR_TAIL: usize = 0x7fff;
 pub const VRAM_ADDR_TOP: usize = 0x8000;
 pub const VRAM_ADDR_TAIL: usize = 0x9fff;
+pub const EXTERNAL_RAM_ADDR_TOP: usize = 0xa000;
+pub const EXTERNAL_RAM_ADDR_TAIL: usize = 0xbfff;
 pub const WRAM_BANK_0_ADDR_TOP: usize = 0xc000;
 pub const WRAM_BANK_0_ADDR_TAIL: usize = 0xcfff;
 pub const WRAM_BANK_1_ADDR_TOP: usize = 0xd000;
@@ -15,8 +19,11 @@ pub const ECHO_RAM_ADDR_TOP: usize = 0xe000;
 pub const ECHO_RAM_ADDR_TAIL: usize = 0xfdff;
 pub const OAM_ADDR_TOP: usize = 0xfe00;
 pub const OAM_ADDR_TAIL: usize = 0xfe9f;
+pub const NOT_USABLE_ADDR_TOP: usize = 0xfea0;
+pub const NOT_USABLE_ADDR_TAIL: usize = 0xfeff;
 pub const IO_PORTS_ADDR_TOP: usize = 0xff00;
 pub const IO_PORTS_ADDR_TAIL: usize = 0xff7f;
+pub const INTERRUPT_FLAG_REG_ADDR: usize = 0xff0f;
 pub const HRAM_ADDR_TOP: usize = 0xff80;
 pub const HRAM_ADDR_TAIL: usize = 0xfffe;
 pub const INTERRUPT_ENABLE_REG_ADDR: usize = 0xffff;
@@ -0,0 +1,354 @@
+use flate2::Crc;
+
+use crate::error::*;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+// source, target and patch crc32 at the end of ups and bps patches
+const FOOTER_SIZE: usize = 12;
+// the largest rom a header can declare, patches for bigger ones are broken
+const MAX_TARGET_SIZE: usize = 0x800000;
+
+pub const PATCH_EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchKind {
+    Ips,
+    Bps,
+    Ups,
+}
+
+impl PatchKind {
+    pub fn detect(patch: &[u8]) -> Option<PatchKind> {
+        if patch.starts_with(IPS_MAGIC) {
+            Some(PatchKind::Ips)
+        } else if patch.starts_with(BPS_MAGIC) {
+            Some(PatchKind::Bps)
+        } else if patch.starts_with(UPS_MAGIC) {
+            Some(PatchKind::Ups)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> GBResult<Vec<u8>> {
+    match PatchKind::detect(patch) {
+        Some(PatchKind::Ips) => apply_ips(rom, patch),
+        Some(PatchKind::Bps) => apply_bps(rom, patch),
+        Some(PatchKind::Ups) => apply_ups(rom, patch),
+        None => Err(invalid("unknown patch format")),
+    }
+}
+
+fn invalid(reason: &str) -> GBError {
+    GBError::InvalidPatch(reason.to_string())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+// reads the patch from the front
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Reader<'a> {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> GBResult<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or_else(|| invalid("unexpected end of patch"))?;
+        let bytes = self.data.get(self.pos..end).ok_or_else(|| invalid("unexpected end of patch"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> GBResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> GBResult<usize> {
+        Ok(self.bytes(len)?.iter().fold(0usize, |x, b| (x << 8) | *b as usize))
+    }
+
+    // the variable length number of beat patches, the continuation bit ends it
+    fn number(&mut self) -> GBResult<usize> {
+        let overflow = || invalid("number overflow");
+        let mut n = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let b = self.byte()?;
+            n = ((b & 0x7f) as usize).checked_mul(shift).and_then(|x| n.checked_add(x)).ok_or_else(overflow)?;
+            if b & 0x80 != 0 {
+                return Ok(n);
+            }
+            // the next 7 bits would be shifted out of the number
+            if shift.leading_zeros() < 7 {
+                return Err(overflow());
+            }
+            shift <<= 7;
+            n = n.checked_add(shift).ok_or_else(overflow)?;
+        }
+    }
+}
+
+// records of a 24bit offset and a 16bit size, a size of 0 is a run of one byte.
+// a 24bit size after EOF truncates the rom
+fn apply_ips(rom: &[u8], patch: &[u8]) -> GBResult<Vec<u8>> {
+    let mut v = rom.to_vec();
+    let mut r = Reader::new(patch, IPS_MAGIC.len());
+    loop {
+        if r.bytes(3)? == IPS_EOF {
+            break;
+        }
+        r.pos -= 3;
+        let offset = r.be(3)?;
+        let (len, data) = match r.be(2)? {
+            0 => {
+                let len = r.be(2)?;
+                (len, vec![r.byte()?; len])
+            },
+            len => (len, r.bytes(len)?.to_vec()),
+        };
+        if v.len() < offset + len {
+            v.resize(offset + len, 0u8);
+        }
+        v[offset..offset + len].copy_from_slice(&data);
+    }
+    if let Ok(len) = r.be(3) {
+        v.truncate(len);
+    }
+    Ok(v)
+}
+
+// checks the crc of the patch and the rom, and returns the crc of the patched rom
+fn footer(rom: &[u8], patch: &[u8], magic_len: usize) -> GBResult<u32> {
+    if patch.len() < magic_len + FOOTER_SIZE {
+        return Err(invalid("unexpected end of patch"));
+    }
+    let word = |i: usize| u32::from_le_bytes([patch[i], patch[i + 1], patch[i + 2], patch[i + 3]]);
+    let tail = patch.len() - FOOTER_SIZE;
+    if crc32(&patch[..patch.len() - 4]) != word(tail + 8) {
+        return Err(invalid("patch crc mismatch"));
+    }
+    if crc32(rom) != word(tail) {
+        return Err(invalid("source rom crc mismatch"));
+    }
+    Ok(word(tail + 4))
+}
+
+// checked before anything is allocated for the patched rom
+fn target_size(size: usize) -> GBResult<usize> {
+    if size > MAX_TARGET_SIZE {
+        return Err(invalid("patched rom too large"));
+    }
+    Ok(size)
+}
+
+fn check_target(v: &[u8], crc: u32) -> GBResult<()> {
+    if crc32(v) != crc {
+        return Err(invalid("patched rom crc mismatch"));
+    }
+    Ok(())
+}
+
+// hunks of a skip and bytes xored with the rom up to a 0
+fn apply_ups(rom: &[u8], patch: &[u8]) -> GBResult<Vec<u8>> {
+    let target_crc = footer(rom, patch, UPS_MAGIC.len())?;
+    let tail = patch.len() - FOOTER_SIZE;
+    let mut r = Reader::new(&patch[..tail], UPS_MAGIC.len());
+    let source_size = r.number()?;
+    let target_size = target_size(r.number()?)?;
+    if source_size != rom.len() {
+        return Err(invalid("source rom size mismatch"));
+    }
+    let mut v = rom.to_vec();
+    v.resize(target_size, 0u8);
+    let mut pos = 0usize;
+    while r.pos < tail {
+        pos = pos.saturating_add(r.number()?);
+        loop {
+            let x = r.byte()?;
+            if let Some(b) = v.get_mut(pos) {
+                *b ^= x;
+            }
+            pos = pos.saturating_add(1);
+            if x == 0 {
+                break;
+            }
+        }
+    }
+    check_target(&v, target_crc)?;
+    Ok(v)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> GBResult<Vec<u8>> {
+    const SOURCE_READ: usize = 0;
+    const TARGET_READ: usize = 1;
+    const SOURCE_COPY: usize = 2;
+    const TARGET_COPY: usize = 3;
+
+    let target_crc = footer(rom, patch, BPS_MAGIC.len())?;
+    let tail = patch.len() - FOOTER_SIZE;
+    let mut r = Reader::new(&patch[..tail], BPS_MAGIC.len());
+    let source_size = r.number()?;
+    let target_size = target_size(r.number()?)?;
+    let metadata = r.number()?;
+    r.bytes(metadata)?;
+    if source_size != rom.len() {
+        return Err(invalid("source rom size mismatch"));
+    }
+    let relative = |base: usize, d: usize| -> GBResult<usize> {
+        let offset = if d & 1 != 0 { base.checked_sub(d >> 1) } else { base.checked_add(d >> 1) };
+        offset.ok_or_else(|| invalid("copy out of range"))
+    };
+    let out_of_range = || invalid("copy out of range");
+
+    let mut v: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source = 0usize;
+    let mut target = 0usize;
+    while r.pos < tail {
+        let data = r.number()?;
+        let len = (data >> 2) + 1;
+        if len > target_size - v.len() {
+            return Err(invalid("patched rom size mismatch"));
+        }
+        match data & 0x03 {
+            SOURCE_READ => {
+                let top = v.len();
+                v.extend_from_slice(rom.get(top..top + len).ok_or_else(out_of_range)?);
+            },
+            TARGET_READ => v.extend_from_slice(r.bytes(len)?),
+            SOURCE_COPY => {
+                source = relative(source, r.number()?)?;
+                v.extend_from_slice(rom.get(source..).and_then(|s| s.get(..len)).ok_or_else(out_of_range)?);
+                source += len;
+            },
+            TARGET_COPY => {
+                target = relative(target, r.number()?)?;
+                // the copy may overlap the bytes it writes, so it goes byte by byte
+                for _ in 0..len {
+                    let b = *v.get(target).ok_or_else(out_of_range)?;
+                    v.push(b);
+                    target += 1;
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+    if v.len() != target_size {
+        return Err(invalid("patched rom size mismatch"));
+    }
+    check_target(&v, target_crc)?;
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut n: usize) -> Vec<u8> {
+        let mut v = vec![];
+        loop {
+            let x = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                v.push(0x80 | x);
+                return v;
+            }
+            v.push(x);
+            n -= 1;
+        }
+    }
+
+    fn beat(magic: &[u8], body: &[u8], source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut v = magic.to_vec();
+        v.extend_from_slice(body);
+        v.extend_from_slice(&crc32(source).to_le_bytes());
+        v.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&v);
+        v.extend_from_slice(&crc.to_le_bytes());
+        v
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xaa, 0xbb]);
+        // a run of 3 bytes beyond the end
+        patch.extend_from_slice(&[0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03, 0xcc]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&[0u8; 4], &patch).unwrap(), vec![0x00, 0xaa, 0xbb, 0x00, 0x00, 0xcc, 0xcc, 0xcc]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x03]);
+        assert_eq!(apply(&[0u8; 4], &patch).unwrap(), vec![0x00, 0xaa, 0xbb]);
+        assert!(matches!(apply(&[0u8; 4], b"PATCH\x00\x00"), Err(GBError::InvalidPatch(_))));
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = [0x10u8, 0x20, 0x30, 0x40];
+        let target = [0x10u8, 0x21, 0x30, 0x40, 0x50];
+        let mut body = number(4);
+        body.extend(number(5));
+        body.extend(number(1));
+        body.extend_from_slice(&[0x01, 0x00]);
+        // the 0 closing a hunk counts as a byte
+        body.extend(number(1));
+        body.extend_from_slice(&[0x50, 0x00]);
+        let patch = beat(UPS_MAGIC, &body, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target.to_vec());
+        assert!(matches!(apply(&[0u8; 4], &patch), Err(GBError::InvalidPatch(_))));
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = [0x01u8, 0x02, 0x03, 0x04];
+        let target = [0x01u8, 0x02, 0xff, 0x03, 0x04, 0x03, 0x04, 0x03];
+        let mut body = number(4);
+        body.extend(number(8));
+        body.extend(number(3));
+        body.extend_from_slice(b"abc");
+        // source read 2, target read 1, source copy 2 from 2, target copy 3 from 3
+        body.extend(number(1 << 2));
+        body.extend(number(1));
+        body.push(0xff);
+        body.extend(number((1 << 2) | 2));
+        body.extend(number(2 << 1));
+        body.extend(number((2 << 2) | 3));
+        body.extend(number(3 << 1));
+        let patch = beat(BPS_MAGIC, &body, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target.to_vec());
+
+        let mut broken = patch.clone();
+        broken[6] ^= 0xff;
+        assert!(matches!(apply(&source, &broken), Err(GBError::InvalidPatch(_))));
+
+        // sizes beyond any rom are rejected before allocating
+        let mut body = number(4);
+        body.extend(number((usize::MAX >> 1) + 1));
+        body.extend(number(0));
+        let patch = beat(BPS_MAGIC, &body, &source, &target);
+        assert!(matches!(apply(&source, &patch), Err(GBError::InvalidPatch(_))));
+        let patch = beat(UPS_MAGIC, &body, &source, &target);
+        assert!(matches!(apply(&source, &patch), Err(GBError::InvalidPatch(_))));
+
+        // numbers longer than a usize and numbers cut off by the end of the patch
+        let mut body = number(4);
+        body.extend_from_slice(&[0x7f; 16]);
+        body.push(0x80);
+        for magic in [BPS_MAGIC, UPS_MAGIC] {
+            let patch = beat(magic, &body, &source, &target);
+            assert!(matches!(apply(&source, &patch), Err(GBError::InvalidPatch(_))));
+        }
+        let mut r = Reader::new(&[0x7f, 0x7f], 0);
+        assert!(matches!(r.number(), Err(GBError::InvalidPatch(_))));
+    }
+}
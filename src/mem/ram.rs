@@ -1,13 +1,72 @@
+use crate::device::Device;
+use crate::error::*;
 
 #[derive(Debug)]
 pub struct Ram {
-    inner: Vec<u8>
+    inner: Vec<u8>,
+    changed: bool, // a byte was written with a new value
 }
 
 impl Ram {
     pub fn new(v: Vec<u8>) -> Ram {
         Ram {
             inner: v.clone(),
+            changed: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.inner
+    }
+
+    pub fn resize(&mut self, len: usize) {
+        self.inner.resize(len, 0u8);
+    }
+
+    // the byte at an offset into all the banks, which the 16bit addresses of read cannot reach
+    pub fn get(&self, offset: usize) -> Option<u8> {
+        self.inner.get(offset).copied()
+    }
+
+    // true once after the contents changed
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    pub fn set(&mut self, offset: usize, val: u8) -> bool {
+        match self.inner.get_mut(offset) {
+            Some(b) => {
+                self.changed |= *b != val;
+                *b = val;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+// addr is the offset from the top of the ram
+impl Device for Ram {
+    fn read(&self, addr: u16) -> GBResult<u8> {
+        self.inner.get(addr as usize).copied().ok_or(GBError::InvalidAddress(addr))
+    }
+
+    fn write(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        match self.inner.get_mut(addr as usize) {
+            Some(b) => {
+                self.changed |= *b != val;
+                *b = val;
+                Ok(())
+            },
+            None => Err(GBError::InvalidAddress(addr)),
         }
     }
 }
@@ -1,3 +1,5 @@
+use crate::device::Device;
+use crate::error::*;
 
 #[derive(Debug)]
 pub struct Rom {
@@ -10,4 +12,43 @@ impl Rom {
             inner: v.clone(),
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.inner
+    }
+
+    // the byte at an offset into the whole image, where the mbc offsets of the banks point
+    pub fn get(&self, offset: usize) -> Option<u8> {
+        self.inner.get(offset).copied()
+    }
+
+    pub fn set(&mut self, offset: usize, val: u8) -> bool {
+        match self.inner.get_mut(offset) {
+            Some(b) => {
+                *b = val;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+// addr is the offset from the top of the rom
+impl Device for Rom {
+    fn read(&self, addr: u16) -> GBResult<u8> {
+        self.inner.get(addr as usize).copied().ok_or(GBError::InvalidAddress(addr))
+    }
+
+    // rom is read only
+    fn write(&mut self, addr: u16, _val: u8) -> GBResult<()> {
+        Err(GBError::InvalidAddress(addr))
+    }
 }
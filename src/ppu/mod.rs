@@ -0,0 +1,436 @@
+mod render;
+
+use crate::device::Device;
+use crate::error::*;
+use crate::mem::ram::Ram;
+use crate::mem::*;
+
+pub const VRAM_BANK_SIZE: usize = 0x2000;
+
+pub const LCDC_ADDR: u16 = 0xff40;
+pub const STAT_ADDR: u16 = 0xff41;
+pub const SCY_ADDR: u16 = 0xff42;
+pub const SCX_ADDR: u16 = 0xff43;
+pub const LY_ADDR: u16 = 0xff44;
+pub const LYC_ADDR: u16 = 0xff45;
+pub const BGP_ADDR: u16 = 0xff47;
+pub const OBP0_ADDR: u16 = 0xff48;
+pub const OBP1_ADDR: u16 = 0xff49;
+pub const WY_ADDR: u16 = 0xff4a;
+pub const WX_ADDR: u16 = 0xff4b;
+pub const VRAM_BANK_ADDR: u16 = 0xff4f;
+
+// bits of the interrupt flag register
+pub const VBLANK_INTERRUPT: u8 = 0b0000_0001;
+pub const STAT_INTERRUPT: u8 = 0b0000_0010;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const LCDC_BG_ENABLE: u8 = 0b0000_0001;
+const LCDC_OBJ_ENABLE: u8 = 0b0000_0010;
+const LCDC_OBJ_SIZE: u8 = 0b0000_0100;
+const LCDC_WINDOW_ENABLE: u8 = 0b0010_0000;
+const LCDC_WINDOW_MAP: u8 = 0b0100_0000;
+const LCDC_TILE_DATA: u8 = 0b0001_0000;
+const LCDC_BG_MAP: u8 = 0b0000_1000;
+const LCDC_ENABLE: u8 = 0b1000_0000;
+
+const STAT_LYC_SOURCE: u8 = 0b0100_0000;
+const STAT_OAM_SOURCE: u8 = 0b0010_0000;
+const STAT_VBLANK_SOURCE: u8 = 0b0001_0000;
+const STAT_HBLANK_SOURCE: u8 = 0b0000_1000;
+const STAT_SOURCES: u8 = 0b0111_1000;
+
+// a dot is a cycle of the cpu at normal speed
+pub const LINE_DOTS: u32 = 456;
+pub const OAM_SCAN_DOTS: u32 = 80;
+const DRAWING_DOTS: u32 = 172;
+const LINES: u8 = 154;
+// ly of the last line reads 153 only for its first machine cycle, then 0
+const LY_153_DOTS: u32 = 4;
+
+// lcd status mode, the cpu cannot reach vram in drawing and oam in oam scan or drawing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LcdMode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+#[derive(Debug)]
+pub struct Ppu {
+    vram: Ram,
+    vram_bank: u8,
+    oam: Ram,
+    lcdc: u8,
+    stat: u8, // only the interrupt sources, the rest is computed
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: LcdMode,
+    line: u8, // the line being drawn, ly differs from it on the last line
+    dot: u32,
+    first_line: bool, // the line after the lcd is turned on has no oam scan
+    stat_line: bool, // the sources are ored, an interrupt is requested on the rising edge only
+    interrupt: u8,
+    window_line: u8, // lines of the window drawn in this frame
+    window_triggered: bool, // ly matched wy in this frame, the window shows from then on
+    objects: Vec<usize>, // oam indices of the objects on the line, found by the oam scan
+    cgb: bool,
+    bg_line: Vec<u8>, // color numbers of the background of the line being drawn
+    frame: Vec<u8>, // shades 0-3 from white to black
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            vram: Ram::new(vec![0u8; VRAM_BANK_SIZE * 2]),
+            vram_bank: 0u8,
+            oam: Ram::new(vec![0u8; OAM_ADDR_TAIL - OAM_ADDR_TOP + 1]),
+            lcdc: 0u8,
+            stat: 0u8,
+            scy: 0u8,
+            scx: 0u8,
+            ly: 0u8,
+            lyc: 0u8,
+            bgp: 0u8,
+            obp0: 0u8,
+            obp1: 0u8,
+            wy: 0u8,
+            wx: 0u8,
+            mode: LcdMode::HBlank,
+            line: 0u8,
+            dot: 0u32,
+            first_line: false,
+            stat_line: false,
+            interrupt: 0u8,
+            window_line: 0u8,
+            window_triggered: false,
+            objects: Vec::new(),
+            cgb: false,
+            bg_line: vec![0u8; SCREEN_WIDTH],
+            frame: vec![0u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn vram(&self) -> &Ram {
+        &self.vram
+    }
+
+    pub fn vram_mut(&mut self) -> &mut Ram {
+        &mut self.vram
+    }
+
+    pub fn oam(&self) -> &Ram {
+        &self.oam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut Ram {
+        &mut self.oam
+    }
+
+    // SCREEN_WIDTH x SCREEN_HEIGHT shades, complete when vblank starts
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.cgb
+    }
+
+    // cgb mode orders overlapping objects by their oam index instead of their x coordinate
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.lcdc & LCDC_ENABLE != 0
+    }
+
+    pub fn mode(&self) -> LcdMode {
+        self.mode
+    }
+
+    // forces the mode until the next change of the state machine, for debugging
+    pub fn set_mode(&mut self, mode: LcdMode) {
+        self.mode = mode;
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    pub fn dot(&self) -> u32 {
+        self.dot
+    }
+
+    // moves within the line, for debugging
+    pub fn set_dot(&mut self, dot: u32) {
+        self.dot = dot % LINE_DOTS;
+    }
+
+    // oam scan reads a row of 8 bytes every 4 dots
+    pub fn oam_scan_row(&self) -> usize {
+        (self.dot.min(OAM_SCAN_DOTS - 1) / 4) as usize
+    }
+
+    fn vram_offset(&self, addr: u16) -> u16 {
+        (self.vram_bank as usize * VRAM_BANK_SIZE + addr as usize - VRAM_ADDR_TOP) as u16
+    }
+
+    fn mode_at(&self) -> LcdMode {
+        if self.line as usize >= SCREEN_HEIGHT {
+            LcdMode::VBlank
+        } else if self.dot < OAM_SCAN_DOTS {
+            if self.first_line { LcdMode::HBlank } else { LcdMode::OamScan }
+        } else if self.dot < OAM_SCAN_DOTS + DRAWING_DOTS {
+            LcdMode::Drawing
+        } else {
+            LcdMode::HBlank
+        }
+    }
+
+    fn dots_until_change(&self) -> u32 {
+        let changes: &[u32] = match self.line {
+            n if (n as usize) < SCREEN_HEIGHT => &[OAM_SCAN_DOTS, OAM_SCAN_DOTS + DRAWING_DOTS, LINE_DOTS],
+            n if n == LINES - 1 => &[LY_153_DOTS, LINE_DOTS],
+            _ => &[LINE_DOTS],
+        };
+        changes.iter().find(|dot| **dot > self.dot).unwrap_or(&LINE_DOTS) - self.dot
+    }
+
+    // cycles until the mode or ly changes, None while the lcd is off
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        if !self.enabled() {
+            return None;
+        }
+        Some(self.dots_until_change() as u64)
+    }
+
+    // the interrupts requested in the cycles
+    pub fn tick(&mut self, cycles: usize) -> u8 {
+        if !self.enabled() {
+            return 0;
+        }
+        let mut dots = cycles;
+        while dots > 0 {
+            let n = (self.dots_until_change() as usize).min(dots);
+            self.dot += n as u32;
+            dots -= n;
+            if self.dot == LINE_DOTS {
+                self.dot = 0;
+                self.line = (self.line + 1) % LINES;
+                self.first_line = false;
+            }
+            self.update();
+        }
+        std::mem::replace(&mut self.interrupt, 0)
+    }
+
+    fn update(&mut self) {
+        let mode = self.mode_at();
+        if mode != self.mode {
+            self.mode = mode;
+            match mode {
+                LcdMode::Drawing => self.scan_oam(),
+                // the line is drawn at once with the registers at the end of drawing
+                LcdMode::HBlank if (self.line as usize) < SCREEN_HEIGHT && self.dot > 0 => self.render_line(),
+                LcdMode::VBlank => {
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                    self.interrupt |= VBLANK_INTERRUPT;
+                },
+                _ => {},
+            }
+        }
+        self.ly = if self.line == LINES - 1 && self.dot >= LY_153_DOTS { 0 } else { self.line };
+        self.update_stat();
+    }
+
+    fn stat_signal(&self) -> bool {
+        // the oam source also fires when vblank starts
+        let oam = self.mode == LcdMode::OamScan || (self.line as usize == SCREEN_HEIGHT && self.dot == 0);
+        (self.stat & STAT_LYC_SOURCE != 0 && self.ly == self.lyc)
+            || (self.stat & STAT_OAM_SOURCE != 0 && oam)
+            || (self.stat & STAT_VBLANK_SOURCE != 0 && self.mode == LcdMode::VBlank)
+            || (self.stat & STAT_HBLANK_SOURCE != 0 && self.mode == LcdMode::HBlank)
+    }
+
+    fn update_stat(&mut self) {
+        if !self.enabled() {
+            return;
+        }
+        let line = self.stat_signal();
+        if line && !self.stat_line {
+            self.interrupt |= STAT_INTERRUPT;
+        }
+        self.stat_line = line;
+    }
+
+    // sets a register without the side effects of a write, like the lcd restarting or
+    // a stat interrupt, for debuggers
+    pub fn set_register(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        match addr {
+            LCDC_ADDR => self.lcdc = val,
+            STAT_ADDR => self.stat = val & STAT_SOURCES,
+            LYC_ADDR => self.lyc = val,
+            _ => return self.write(addr, val),
+        }
+        // the interrupt line follows without an edge
+        self.stat_line = self.enabled() && self.stat_signal();
+        Ok(())
+    }
+
+    fn write_lcdc(&mut self, val: u8) {
+        let enabled = self.enabled();
+        self.lcdc = val;
+        if enabled == self.enabled() {
+            return;
+        }
+        // the lcd stops at the top of the screen and starts from there
+        self.line = 0;
+        self.dot = 0;
+        self.ly = 0;
+        self.mode = LcdMode::HBlank;
+        self.stat_line = false;
+        self.first_line = self.enabled();
+        self.window_line = 0;
+        self.window_triggered = false;
+        if !self.enabled() {
+            self.frame.fill(0);
+        }
+        self.update_stat();
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu::new()
+    }
+}
+
+impl Device for Ppu {
+    fn read(&self, addr: u16) -> GBResult<u8> {
+        match addr as usize {
+            VRAM_ADDR_TOP..=VRAM_ADDR_TAIL => self.vram.read(self.vram_offset(addr)),
+            OAM_ADDR_TOP..=OAM_ADDR_TAIL => self.oam.read(addr - OAM_ADDR_TOP as u16),
+            _ => match addr {
+                LCDC_ADDR => Ok(self.lcdc),
+                STAT_ADDR => Ok(0x80 | self.stat | ((self.ly == self.lyc) as u8) << 2 | self.mode as u8),
+                SCY_ADDR => Ok(self.scy),
+                SCX_ADDR => Ok(self.scx),
+                LY_ADDR => Ok(self.ly),
+                LYC_ADDR => Ok(self.lyc),
+                BGP_ADDR => Ok(self.bgp),
+                OBP0_ADDR => Ok(self.obp0),
+                OBP1_ADDR => Ok(self.obp1),
+                WY_ADDR => Ok(self.wy),
+                WX_ADDR => Ok(self.wx),
+                VRAM_BANK_ADDR => Ok(self.vram_bank | 0b1111_1110),
+                _ => Err(GBError::InvalidAddress(addr)),
+            },
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        match addr as usize {
+            VRAM_ADDR_TOP..=VRAM_ADDR_TAIL => return self.vram.write(self.vram_offset(addr), val),
+            OAM_ADDR_TOP..=OAM_ADDR_TAIL => return self.oam.write(addr - OAM_ADDR_TOP as u16, val),
+            _ => {},
+        }
+        match addr {
+            LCDC_ADDR => self.write_lcdc(val),
+            STAT_ADDR => {
+                self.stat = val & STAT_SOURCES;
+                self.update_stat();
+            },
+            SCY_ADDR => self.scy = val,
+            SCX_ADDR => self.scx = val,
+            // ly is read only
+            LY_ADDR => {},
+            LYC_ADDR => {
+                self.lyc = val;
+                self.update_stat();
+            },
+            BGP_ADDR => self.bgp = val,
+            OBP0_ADDR => self.obp0 = val,
+            OBP1_ADDR => self.obp1 = val,
+            WY_ADDR => self.wy = val,
+            WX_ADDR => self.wx = val,
+            VRAM_BANK_ADDR => self.vram_bank = val & 0b0000_0001,
+            _ => return Err(GBError::InvalidAddress(addr)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.write(LCDC_ADDR, LCDC_ENABLE).unwrap();
+        // skip the first line which has no oam scan
+        ppu.tick(LINE_DOTS as usize);
+        ppu
+    }
+
+    #[test]
+    fn test_ppu_modes() {
+        let mut ppu = ppu();
+        assert_eq!((ppu.ly(), ppu.mode()), (1, LcdMode::OamScan));
+        assert_eq!(ppu.cycles_until_event(), Some(80));
+        ppu.tick(80);
+        assert_eq!(ppu.mode(), LcdMode::Drawing);
+        ppu.tick(172);
+        assert_eq!(ppu.mode(), LcdMode::HBlank);
+        assert_eq!(ppu.read(STAT_ADDR).unwrap() & 0x03, 0);
+
+        // vblank from line 144 to 153, a frame is 154 lines
+        let irq = ppu.tick(204 + 142 * LINE_DOTS as usize);
+        assert_eq!((ppu.ly(), ppu.mode(), irq), (144, LcdMode::VBlank, VBLANK_INTERRUPT));
+        ppu.tick(9 * LINE_DOTS as usize);
+        assert_eq!(ppu.ly(), 153);
+        ppu.tick(4);
+        assert_eq!((ppu.ly(), ppu.mode()), (0, LcdMode::VBlank));
+        ppu.tick(LINE_DOTS as usize - 4);
+        assert_eq!((ppu.ly(), ppu.mode()), (0, LcdMode::OamScan));
+
+        ppu.write(LCDC_ADDR, 0x00).unwrap();
+        assert_eq!((ppu.ly(), ppu.mode(), ppu.cycles_until_event()), (0, LcdMode::HBlank, None));
+    }
+
+    #[test]
+    fn test_ppu_stat_interrupt() {
+        let mut ppu = ppu();
+        ppu.write(LYC_ADDR, 2).unwrap();
+        ppu.write(STAT_ADDR, STAT_LYC_SOURCE | STAT_HBLANK_SOURCE).unwrap();
+        // hblank of line 1
+        assert_eq!(ppu.tick(252), STAT_INTERRUPT);
+        // the line stays high from the hblank to the coincidence on line 2, so it blocks the interrupt
+        assert_eq!(ppu.tick(204), 0);
+        assert_ne!(ppu.read(STAT_ADDR).unwrap() & 0b100, 0);
+        // high until line 3, then the hblank of line 3 raises it again
+        assert_eq!(ppu.tick(LINE_DOTS as usize), 0);
+        assert_eq!(ppu.tick(252), STAT_INTERRUPT);
+
+        // coincidence with 0 holds from the fifth dot of line 153 through line 0
+        let mut ppu = self::ppu();
+        ppu.write(LYC_ADDR, 0).unwrap();
+        ppu.write(STAT_ADDR, STAT_LYC_SOURCE).unwrap();
+        ppu.tick(152 * LINE_DOTS as usize);
+        assert_eq!(ppu.ly(), 153);
+        assert_eq!(ppu.tick(4), STAT_INTERRUPT);
+        assert_eq!(ppu.tick(LINE_DOTS as usize), 0);
+        assert_eq!(ppu.ly(), 0);
+    }
+}
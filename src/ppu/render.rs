@@ -0,0 +1,303 @@
+use super::*;
+
+// offsets in vram of the two 32x32 tile maps
+const TILE_MAP_0: usize = 0x1800;
+const TILE_MAP_1: usize = 0x1c00;
+const TILE_SIZE: usize = 16;
+// the window is placed at wx - 7, at wx of 167 and above it is off screen
+const WINDOW_X_OFFSET: usize = 7;
+
+const OBJECTS: usize = 40;
+const LINE_OBJECTS: usize = 10;
+// objects are placed at x - 8, y - 16 so that they can scroll in from the top left
+const OBJ_X_OFFSET: usize = 8;
+const OBJ_Y_OFFSET: usize = 16;
+
+// bits of the object attributes
+const OBJ_BEHIND_BG: u8 = 0b1000_0000;
+const OBJ_Y_FLIP: u8 = 0b0100_0000;
+const OBJ_X_FLIP: u8 = 0b0010_0000;
+const OBJ_PALETTE: u8 = 0b0001_0000;
+const OBJ_BANK: u8 = 0b0000_1000;
+
+impl Ppu {
+    fn vram_byte(&self, offset: usize) -> u8 {
+        self.vram.get(offset).unwrap_or(0)
+    }
+
+    // the background and window use tiles 0-127 at 0x8000 or -128-127 around 0x9000
+    fn bg_tile_offset(&self, tile: u8) -> usize {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile as usize * TILE_SIZE
+        } else {
+            (0x1000 + tile as i8 as isize * TILE_SIZE as isize) as usize
+        }
+    }
+
+    // color number 0-3 of a pixel, two bit planes per row of 8 pixels with the leftmost in bit 7
+    fn tile_color(&self, offset: usize, x: usize, y: usize) -> u8 {
+        let lo = self.vram_byte(offset + y * 2);
+        let hi = self.vram_byte(offset + y * 2 + 1);
+        let bit = 7 - x;
+        ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1)
+    }
+
+    // color number of the pixel at x, y of the 256x256 layer drawn from a tile map
+    fn map_color(&self, map: usize, x: usize, y: usize) -> u8 {
+        let tile = self.vram_byte(map + (y / 8) * 32 + x / 8);
+        self.tile_color(self.bg_tile_offset(tile), x % 8, y % 8)
+    }
+
+    fn window_visible(&self) -> bool {
+        self.lcdc & LCDC_BG_ENABLE != 0 && self.lcdc & LCDC_WINDOW_ENABLE != 0
+            && self.window_triggered && (self.wx as usize) < SCREEN_WIDTH + WINDOW_X_OFFSET
+    }
+
+    fn oam_byte(&self, offset: usize) -> u8 {
+        self.oam.get(offset).unwrap_or(0)
+    }
+
+    fn obj_height(&self) -> usize {
+        if self.lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 }
+    }
+
+    // the first 10 objects in oam which cover the line, whether they are on screen horizontally or not
+    pub(super) fn scan_oam(&mut self) {
+        let y = self.line as usize + OBJ_Y_OFFSET;
+        let height = self.obj_height();
+        self.objects = (0..OBJECTS)
+            .filter(|index| {
+                let top = self.oam_byte(index * 4) as usize;
+                top <= y && y < top + height
+            })
+            .take(LINE_OBJECTS)
+            .collect();
+    }
+
+    // shade and bg over obj bit of the object drawn at each pixel of the line
+    fn object_line(&self) -> [Option<(u8, bool)>; SCREEN_WIDTH] {
+        let mut line = [None; SCREEN_WIDTH];
+        let mut objects = self.objects.clone();
+        // dmg draws the object with the smallest x on top, then the first in oam
+        if !self.cgb {
+            objects.sort_by_key(|index| self.oam_byte(index * 4 + 1));
+        }
+        let height = self.obj_height();
+        // lowest priority first, so the ones above overwrite it
+        for index in objects.into_iter().rev() {
+            let top = self.oam_byte(index * 4) as usize;
+            let left = self.oam_byte(index * 4 + 1) as usize;
+            let attr = self.oam_byte(index * 4 + 3);
+            // 8x16 objects use the even tile for the upper half and the odd one for the lower
+            let tile = if height == 16 { self.oam_byte(index * 4 + 2) & 0xfe } else { self.oam_byte(index * 4 + 2) };
+            let mut row = self.line as usize + OBJ_Y_OFFSET - top;
+            if attr & OBJ_Y_FLIP != 0 {
+                row = height - 1 - row;
+            }
+            let mut offset = tile as usize * TILE_SIZE;
+            if self.cgb && attr & OBJ_BANK != 0 {
+                offset += VRAM_BANK_SIZE;
+            }
+            let palette = if attr & OBJ_PALETTE != 0 { self.obp1 } else { self.obp0 };
+            for col in 0..8 {
+                let x = left + col;
+                if !(OBJ_X_OFFSET..SCREEN_WIDTH + OBJ_X_OFFSET).contains(&x) {
+                    continue;
+                }
+                let col = if attr & OBJ_X_FLIP != 0 { 7 - col } else { col };
+                // color 0 is transparent and shows the object below
+                match self.tile_color(offset, col, row) {
+                    0 => {},
+                    color => line[x - OBJ_X_OFFSET] = Some((shade(palette, color), attr & OBJ_BEHIND_BG != 0)),
+                }
+            }
+        }
+        line
+    }
+
+    pub(super) fn render_line(&mut self) {
+        let bg_map = if self.lcdc & LCDC_BG_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+        let window_map = if self.lcdc & LCDC_WINDOW_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+        // the window is only triggered by ly matching wy, moving wy past ly does not show it
+        if self.line == self.wy {
+            self.window_triggered = true;
+        }
+        let window = self.window_visible();
+        let y = self.line as usize;
+        for x in 0..SCREEN_WIDTH {
+            let color = if self.lcdc & LCDC_BG_ENABLE == 0 {
+                0
+            } else if window && x + WINDOW_X_OFFSET >= self.wx as usize {
+                self.map_color(window_map, x + WINDOW_X_OFFSET - self.wx as usize, self.window_line as usize)
+            } else {
+                // the background wraps around at the edges of the map
+                self.map_color(bg_map, (x + self.scx as usize) % 256, (y + self.scy as usize) % 256)
+            };
+            self.bg_line[x] = color;
+            self.frame[y * SCREEN_WIDTH + x] = shade(self.bgp, color);
+        }
+        // the window resumes from the line after the last one it drew
+        if window {
+            self.window_line += 1;
+        }
+        if self.lcdc & LCDC_OBJ_ENABLE == 0 {
+            return;
+        }
+        for (x, object) in self.object_line().iter().enumerate() {
+            match object {
+                // with the bg over obj bit the object only shows through color 0 of the background
+                Some((_, true)) if self.bg_line[x] != 0 => {},
+                Some((val, _)) => self.frame[y * SCREEN_WIDTH + x] = *val,
+                None => {},
+            }
+        }
+    }
+}
+
+fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // tile 1 has color 3 in its left half, tile 2 is filled with color 1
+    fn ppu(lcdc: u8) -> Ppu {
+        let mut ppu = Ppu::new();
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, 0xf0).unwrap();
+            ppu.write(0x8011 + row * 2, 0xf0).unwrap();
+            ppu.write(0x8020 + row * 2, 0xff).unwrap();
+        }
+        ppu.write(BGP_ADDR, 0b11_10_01_00).unwrap();
+        ppu.write(LCDC_ADDR, LCDC_ENABLE | lcdc).unwrap();
+        ppu
+    }
+
+    fn frame(ppu: &mut Ppu) -> Vec<u8> {
+        ppu.tick(LINE_DOTS as usize * LINES as usize);
+        ppu.frame().to_vec()
+    }
+
+    #[test]
+    fn test_render_background() {
+        let mut ppu = ppu(LCDC_BG_ENABLE | LCDC_TILE_DATA);
+        ppu.write(0x9800, 1).unwrap();
+        let f = frame(&mut ppu);
+        assert_eq!(&f[..9], &[3, 3, 3, 3, 0, 0, 0, 0, 0]);
+        assert_eq!(f[7 * SCREEN_WIDTH + 3], 3);
+        assert_eq!(f[8 * SCREEN_WIDTH], 0);
+
+        // scrolling wraps around to the last column and row of the map
+        ppu.write(SCX_ADDR, 0xfe).unwrap();
+        ppu.write(SCY_ADDR, 0xfc).unwrap();
+        let f = frame(&mut ppu);
+        assert_eq!(&f[4 * SCREEN_WIDTH..4 * SCREEN_WIDTH + 7], &[0, 0, 3, 3, 3, 3, 0]);
+        assert_eq!(f[3 * SCREEN_WIDTH + 2], 0);
+
+        // signed tile numbers from 0x9000, with the palette applied
+        ppu.write(LCDC_ADDR, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_BG_MAP).unwrap();
+        ppu.write(SCX_ADDR, 0).unwrap();
+        ppu.write(SCY_ADDR, 0).unwrap();
+        ppu.write(0x9c00, 0x80).unwrap();
+        ppu.write(0x8800, 0xff).unwrap();
+        ppu.write(BGP_ADDR, 0b00_00_10_00).unwrap();
+        assert_eq!(&frame(&mut ppu)[..2], &[2, 2]);
+    }
+
+    fn object(ppu: &mut Ppu, index: u16, y: u8, x: u8, tile: u8, attr: u8) {
+        for (i, val) in [y, x, tile, attr].iter().enumerate() {
+            ppu.write(0xfe00 + index * 4 + i as u16, *val).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_render_objects() {
+        let mut ppu = ppu(LCDC_BG_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE);
+        ppu.write(OBP0_ADDR, 0b11_10_01_00).unwrap();
+        ppu.write(OBP1_ADDR, 0b01_00_00_00).unwrap();
+        object(&mut ppu, 0, 16, 8, 1, 0);
+        object(&mut ppu, 1, 16, 20, 1, OBJ_X_FLIP | OBJ_PALETTE);
+        let f = frame(&mut ppu);
+        assert_eq!(&f[..8], &[3, 3, 3, 3, 0, 0, 0, 0]);
+        assert_eq!(&f[12..20], &[0, 0, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(f[8 * SCREEN_WIDTH], 0);
+
+        // behind the background the object only shows over its color 0
+        ppu.write(0x9800, 1).unwrap();
+        object(&mut ppu, 0, 16, 8, 2, OBJ_BEHIND_BG);
+        assert_eq!(&frame(&mut ppu)[..8], &[3, 3, 3, 3, 1, 1, 1, 1]);
+        ppu.write(LCDC_ADDR, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_TILE_DATA).unwrap();
+        assert_eq!(&frame(&mut ppu)[..8], &[3, 3, 3, 3, 0, 0, 0, 0]);
+
+        // 8x16 objects ignore bit 0 of the tile, flipped the lower tile comes first
+        let mut ppu = self::ppu(LCDC_TILE_DATA | LCDC_OBJ_ENABLE | LCDC_OBJ_SIZE);
+        ppu.write(OBP0_ADDR, 0b11_10_01_00).unwrap();
+        object(&mut ppu, 0, 16, 8, 3, OBJ_Y_FLIP);
+        let f = frame(&mut ppu);
+        assert_eq!((f[7 * SCREEN_WIDTH], f[8 * SCREEN_WIDTH], f[15 * SCREEN_WIDTH + 7], f[16 * SCREEN_WIDTH]), (0, 1, 1, 0));
+    }
+
+    #[test]
+    fn test_render_object_priority() {
+        let mut ppu = ppu(LCDC_TILE_DATA | LCDC_OBJ_ENABLE);
+        ppu.write(OBP0_ADDR, 0b11_10_01_00).unwrap();
+        ppu.write(OBP1_ADDR, 0b11_11_11_00).unwrap();
+        // only the first 10 objects of a line are drawn, even those off screen count
+        object(&mut ppu, 0, 24, 0, 2, 0);
+        for i in 1..11 {
+            object(&mut ppu, i, 24, i as u8 * 8, 2, 0);
+        }
+        let f = frame(&mut ppu);
+        assert_eq!((f[8 * SCREEN_WIDTH + 8 * 8], f[8 * SCREEN_WIDTH + 9 * 8]), (1, 0));
+
+        // dmg draws the smaller x on top, cgb the smaller oam index
+        object(&mut ppu, 0, 16, 48, 2, 0);
+        object(&mut ppu, 1, 16, 44, 2, OBJ_PALETTE);
+        let f = frame(&mut ppu);
+        assert_eq!((f[39], f[40], f[44]), (3, 3, 1));
+        ppu.set_cgb(true);
+        let f = frame(&mut ppu);
+        assert_eq!((f[39], f[40], f[44]), (3, 1, 1));
+    }
+
+    #[test]
+    fn test_render_window() {
+        let mut ppu = ppu(LCDC_BG_ENABLE | LCDC_TILE_DATA | LCDC_WINDOW_ENABLE | LCDC_WINDOW_MAP);
+        for offset in TILE_MAP_1..TILE_MAP_1 + 0x400 {
+            ppu.vram_mut().set(offset, 2);
+        }
+        ppu.write(WX_ADDR, 7 + 100).unwrap();
+        ppu.write(WY_ADDR, 50).unwrap();
+        let f = frame(&mut ppu);
+        assert_eq!((f[49 * SCREEN_WIDTH + 100], f[50 * SCREEN_WIDTH + 99], f[50 * SCREEN_WIDTH + 100]), (0, 0, 1));
+
+        // wy is latched per frame, lowering it below ly does not show the window until the next frame
+        // and raising it past ly does not hide it
+        ppu.write(WY_ADDR, 100).unwrap();
+        ppu.tick(60 * LINE_DOTS as usize);
+        ppu.write(WY_ADDR, 10).unwrap();
+        ppu.tick(10 * LINE_DOTS as usize);
+        assert_eq!(ppu.frame()[65 * SCREEN_WIDTH + 100], 0);
+        ppu.tick((LINES as usize - 70 + 20) * LINE_DOTS as usize);
+        ppu.write(WY_ADDR, 100).unwrap();
+        ppu.tick(10 * LINE_DOTS as usize);
+        assert_eq!(ppu.frame()[25 * SCREEN_WIDTH + 100], 1);
+
+        // the window line counter only counts the lines the window was drawn on
+        let mut ppu = self::ppu(LCDC_BG_ENABLE | LCDC_TILE_DATA | LCDC_WINDOW_ENABLE);
+        ppu.write(0x9800 + 32, 1).unwrap();
+        ppu.write(WX_ADDR, 7).unwrap();
+        ppu.write(WY_ADDR, 0).unwrap();
+        ppu.tick(4 * LINE_DOTS as usize);
+        ppu.write(WX_ADDR, 200).unwrap();
+        ppu.tick(8 * LINE_DOTS as usize);
+        ppu.write(WX_ADDR, 7).unwrap();
+        ppu.tick(LINE_DOTS as usize);
+        assert_eq!(ppu.frame()[12 * SCREEN_WIDTH], 0);
+        ppu.tick(4 * LINE_DOTS as usize);
+        assert_eq!(ppu.frame()[16 * SCREEN_WIDTH], 3);
+        assert_eq!(ppu.frame()[16 * SCREEN_WIDTH + 4], 0);
+    }
+}
@@ -0,0 +1,94 @@
+// the components which run on their own clock, each one has at most one pending event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Timer,
+    Ppu,
+}
+
+const EVENTS: [Event; 2] = [Event::Timer, Event::Ppu];
+
+// keeps the deadline of every component in cpu cycles, so the cpu can run freely
+// until the next one instead of stepping every component after every instruction
+#[derive(Debug)]
+pub struct Scheduler {
+    now: u64,
+    deadlines: [Option<u64>; EVENTS.len()],
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            now: 0u64,
+            deadlines: [None; EVENTS.len()],
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    // replaces the pending event of the component
+    pub fn schedule(&mut self, event: Event, cycles: u64) {
+        self.deadlines[event as usize] = Some(self.now + cycles);
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.deadlines[event as usize] = None;
+    }
+
+    pub fn deadline(&self, event: Event) -> Option<u64> {
+        self.deadlines[event as usize]
+    }
+
+    fn next(&self) -> Option<(Event, u64)> {
+        EVENTS.iter().filter_map(|event| self.deadline(*event).map(|at| (*event, at))).min_by_key(|(_, at)| *at)
+    }
+
+    // 0 when an event is due, None without any pending event
+    pub fn cycles_until_next(&self) -> Option<u64> {
+        self.next().map(|(_, at)| at.saturating_sub(self.now))
+    }
+
+    // the earliest event which is due, it is removed and has to be scheduled again
+    pub fn pop_due(&mut self) -> Option<Event> {
+        match self.next() {
+            Some((event, at)) if at <= self.now => {
+                self.cancel(event);
+                Some(event)
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheduler() {
+        let mut scheduler = Scheduler::new();
+        assert_eq!(scheduler.cycles_until_next(), None);
+        scheduler.schedule(Event::Timer, 100);
+        scheduler.advance(60);
+        assert_eq!(scheduler.cycles_until_next(), Some(40));
+        assert_eq!(scheduler.pop_due(), None);
+        // rescheduling replaces the deadline
+        scheduler.schedule(Event::Timer, 10);
+        scheduler.advance(16);
+        assert_eq!(scheduler.cycles_until_next(), Some(0));
+        assert_eq!(scheduler.pop_due(), Some(Event::Timer));
+        assert_eq!(scheduler.pop_due(), None);
+        assert_eq!((scheduler.now(), scheduler.deadline(Event::Timer)), (76, None));
+    }
+}
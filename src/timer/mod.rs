@@ -1,17 +1,292 @@
+use crate::device::Device;
+use crate::error::*;
 
-#[derive(Debug)]
+pub const DIV_ADDR: u16 = 0xff04;
+pub const TIMA_ADDR: u16 = 0xff05;
+pub const TMA_ADDR: u16 = 0xff06;
+pub const TAC_ADDR: u16 = 0xff07;
+
+// bit of the interrupt flag register
+pub const TIMER_INTERRUPT: u8 = 0b0000_0100;
+
+const TAC_ENABLE: u8 = 0b0000_0100;
+// the timer runs once per machine cycle
+const M_CYCLE: usize = 4;
+
+#[derive(Debug, Clone)]
 pub struct Timer {
+    counter: u16, // internal counter, div is its upper 8 bits
     tima: u8, // timer counter
     tma: u8, // timer modulo
     tac: u8, // timer control 3bit value bit 2 => timer stop, bit 1-0: input clock select
+    overflow: bool, // tima overflowed and reads 0 until it is reloaded in the next machine cycle
+    reloaded: bool, // tima was reloaded from tma in this machine cycle
+    interrupt: bool,
 }
 
 impl Timer {
     pub fn new() -> Timer {
         Timer {
+            counter: 0u16,
             tima: 0u8,
             tma: 0u8,
             tac: 0u8,
+            overflow: false,
+            reloaded: false,
+            interrupt: false,
+        }
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    // tima counts on the falling edge of the counter bit selected by tac, anded with the enable bit
+    fn signal(&self) -> bool {
+        self.enabled() && self.counter & (self.period() >> 1) != 0
+    }
+
+    fn enabled(&self) -> bool {
+        self.tac & TAC_ENABLE != 0
+    }
+
+    // cycles between two increments of tima
+    fn period(&self) -> u16 {
+        match self.tac & 0b0000_0011 {
+            0b00 => 1024,
+            0b01 => 16,
+            0b10 => 64,
+            _ => 256,
+        }
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflow = overflow;
+    }
+
+    fn step(&mut self) {
+        self.reloaded = false;
+        if self.overflow {
+            self.overflow = false;
+            self.tima = self.tma;
+            self.reloaded = true;
+            self.interrupt = true;
+        }
+        let signal = self.signal();
+        self.counter = self.counter.wrapping_add(M_CYCLE as u16);
+        if signal && !self.signal() {
+            self.increment();
+        }
+    }
+
+    // machine cycles in which nothing happens but the counter and tima counting up,
+    // the cycle tima overflows in and the reload after it are stepped one by one
+    fn quiet_steps(&self) -> u64 {
+        if self.overflow || self.reloaded {
+            return 0;
+        }
+        if !self.enabled() {
+            return u64::MAX;
+        }
+        self.overflow_steps() - 1
+    }
+
+    // machine cycles until the one tima overflows in
+    fn overflow_steps(&self) -> u64 {
+        let period = self.period() as u64;
+        let first = (period - self.counter as u64 % period) / M_CYCLE as u64;
+        first + (0xff - self.tima as u64) * period / M_CYCLE as u64
+    }
+
+    fn skip(&mut self, steps: u64) {
+        let counter = self.counter as u64 + steps * M_CYCLE as u64;
+        if self.enabled() {
+            let period = self.period() as u64;
+            self.tima += (counter / period - self.counter as u64 / period) as u8;
+        }
+        self.counter = counter as u16;
+    }
+
+    // true when the timer requests an interrupt, at the same time tima is reloaded after an overflow
+    pub fn tick(&mut self, cycles: usize) -> bool {
+        let mut steps = (cycles / M_CYCLE) as u64;
+        while steps > 0 {
+            let quiet = self.quiet_steps().min(steps);
+            if quiet > 0 {
+                self.skip(quiet);
+                steps -= quiet;
+            } else {
+                self.step();
+                steps -= 1;
+            }
+        }
+        std::mem::replace(&mut self.interrupt, false)
+    }
+
+    // cycles until tima is reloaded and the interrupt is requested, None while the timer is stopped
+    pub fn cycles_until_interrupt(&self) -> Option<u64> {
+        if self.overflow {
+            return Some(M_CYCLE as u64);
+        }
+        if !self.enabled() {
+            return None;
+        }
+        Some((self.overflow_steps() + 1) * M_CYCLE as u64)
+    }
+
+    // sets a register to the value it reads without the side effects of a write, for debuggers
+    pub fn set_register(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        match addr {
+            DIV_ADDR => self.counter = (self.counter & 0x00ff) | (val as u16) << 8,
+            TIMA_ADDR => self.tima = val,
+            TMA_ADDR => self.tma = val,
+            TAC_ADDR => self.tac = val & 0b0000_0111,
+            _ => return Err(GBError::InvalidAddress(addr)),
+        }
+        Ok(())
+    }
+
+    // writes change the signal too, a fall from them counts like one from the counter
+    fn update<F: FnOnce(&mut Timer)>(&mut self, f: F) {
+        let signal = self.signal();
+        f(self);
+        if signal && !self.signal() {
+            self.increment();
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+impl Device for Timer {
+    fn read(&self, addr: u16) -> GBResult<u8> {
+        match addr {
+            DIV_ADDR => Ok(self.div()),
+            TIMA_ADDR => Ok(self.tima),
+            TMA_ADDR => Ok(self.tma),
+            // unused upper bits of tac read as 1
+            TAC_ADDR => Ok(self.tac | 0b1111_1000),
+            _ => Err(GBError::InvalidAddress(addr)),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) -> GBResult<()> {
+        match addr {
+            DIV_ADDR => self.update(|timer| timer.counter = 0),
+            // a write in the cycle after the overflow cancels the reload, in the reload cycle tma wins
+            TIMA_ADDR if !self.reloaded => {
+                self.tima = val;
+                self.overflow = false;
+            },
+            TIMA_ADDR => {},
+            TMA_ADDR => {
+                self.tma = val;
+                if self.reloaded {
+                    self.tima = val;
+                }
+            },
+            TAC_ADDR => self.update(|timer| timer.tac = val & 0b0000_0111),
+            _ => return Err(GBError::InvalidAddress(addr)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_count() {
+        let mut timer = Timer::new();
+        timer.tick(0x100);
+        assert_eq!(timer.read(DIV_ADDR).unwrap(), 0x01);
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x00);
+
+        // 16 cycles per increment
+        timer.write(TAC_ADDR, 0b101).unwrap();
+        timer.tick(16 * 3);
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 3);
+        // resetting div while the selected bit is set increments tima
+        timer.tick(8);
+        timer.write(DIV_ADDR, 0x42).unwrap();
+        assert_eq!((timer.read(DIV_ADDR).unwrap(), timer.read(TIMA_ADDR).unwrap()), (0, 4));
+        // so does disabling the timer
+        timer.tick(8);
+        timer.write(TAC_ADDR, 0b001).unwrap();
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 5);
+        timer.tick(16);
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 5);
+
+        // setting div directly keeps tima
+        timer.write(TAC_ADDR, 0b101).unwrap();
+        // with the selected bit set a write to div would increment tima
+        while timer.counter() & 0b1000 == 0 {
+            timer.tick(4);
+        }
+        let tima = timer.read(TIMA_ADDR).unwrap();
+        timer.set_register(DIV_ADDR, 0x42).unwrap();
+        assert_eq!((timer.read(DIV_ADDR).unwrap(), timer.read(TIMA_ADDR).unwrap()), (0x42, tima));
+    }
+
+    #[test]
+    fn test_timer_overflow() {
+        let mut timer = Timer::new();
+        timer.write(TMA_ADDR, 0x80).unwrap();
+        timer.write(TIMA_ADDR, 0xff).unwrap();
+        timer.write(TAC_ADDR, 0b101).unwrap();
+        assert!(!timer.tick(16));
+        // tima reads 0 for a machine cycle before the reload and the interrupt
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x00);
+        assert!(timer.tick(4));
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x80);
+        // tima ignores writes in the reload cycle, tma goes through
+        timer.write(TIMA_ADDR, 0x10).unwrap();
+        timer.write(TMA_ADDR, 0x90).unwrap();
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x90);
+
+        // a write before the reload cancels it
+        timer.tick(4);
+        timer.write(TIMA_ADDR, 0xff).unwrap();
+        timer.tick(8);
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x00);
+        timer.write(TIMA_ADDR, 0x20).unwrap();
+        assert!(!timer.tick(4));
+        assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x20);
+    }
+
+    #[test]
+    fn test_timer_skip() {
+        // running many cycles at once matches running them machine cycle by machine cycle
+        for tac in [0b000, 0b100, 0b101, 0b110, 0b111] {
+            let timer = || {
+                let mut timer = Timer::new();
+                timer.write(TMA_ADDR, 0xf0).unwrap();
+                timer.write(TAC_ADDR, tac).unwrap();
+                timer.write(TIMA_ADDR, 0xe0).unwrap();
+                timer
+            };
+            let (mut fast, mut slow) = (timer(), timer());
+            let until = fast.cycles_until_interrupt();
+            let irq = fast.tick(50000);
+            let mut first = None;
+            for i in 1..=12500 {
+                if slow.tick(4) && first.is_none() {
+                    first = Some(i * 4);
+                }
+            }
+            assert_eq!((fast.counter(), fast.read(TIMA_ADDR).unwrap()), (slow.counter(), slow.read(TIMA_ADDR).unwrap()));
+            assert_eq!((irq, until), (first.is_some(), first));
         }
     }
 }
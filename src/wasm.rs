@@ -0,0 +1,45 @@
+use wasm_bindgen::prelude::*;
+
+use crate::cartridge::clock::CycleClock;
+use crate::cartridge::Cartridge;
+use crate::mem::ram::Ram;
+use crate::mem::rom::Rom;
+
+// the cartridge handed to javascript, the page keeps the save in browser storage
+// with load_save and flush_save instead of a .sav file
+#[wasm_bindgen]
+pub struct WasmCartridge {
+    inner: Cartridge<'static>,
+}
+
+#[wasm_bindgen]
+impl WasmCartridge {
+    // the host clock is not available in the browser, so the rtc runs with the emulation
+    // from now, the unix time in seconds, e.g. Date.now() / 1000
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: Vec<u8>, now: f64) -> Result<WasmCartridge, JsValue> {
+        let mut inner = Cartridge::owned(Rom::new(rom), Ram::new(vec![])).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        inner.set_clock(CycleClock::new(now as u64));
+        Ok(WasmCartridge { inner })
+    }
+
+    pub fn title(&self) -> String {
+        self.inner.header().title.clone()
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.inner.has_battery()
+    }
+
+    pub fn load_save(&mut self, data: &[u8]) {
+        self.inner.load_save_data(data);
+    }
+
+    // the save when it changed since the last flush
+    pub fn flush_save(&mut self) -> Option<Vec<u8>> {
+        if !self.inner.take_ram_dirty() {
+            return None;
+        }
+        Some(self.inner.save_data())
+    }
+}